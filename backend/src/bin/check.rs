@@ -0,0 +1,99 @@
+use rust_chess::checks::{check_symmetry, check_make_unmake};
+use rust_chess::score_functions::{EvalParams, EVALUATORS, find_evaluator, default_evaluator};
+use rust_chess::utils::parse_fen;
+
+use std::{env, fs, process};
+
+const USAGE: &str = "Usage: check <fens> [--eval <name>] [--depth <n>]
+
+Runs consistency checks on every position in a file with one FEN per line:
+- The evaluation of the position with the colours flipped has to be the negation of its own.
+  Every evaluator that is available is checked, or only the one given by --eval.
+- Making and unmaking every sequence of moves up to --depth plies (default 2) has to keep the
+  hash in sync and restore the board, castling rights, en passant square and hash. With an
+  evaluator that is updated move by move, such as nnue, it also has to match a full evaluation.";
+
+struct Args {
+    fens: String,
+    eval: Option<String>,
+    depth: usize
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = env::args().skip(1);
+    let mut parsed = Args { fens: String::new(), eval: None, depth: 2 };
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {arg}"));
+        match arg.as_str() {
+            "--eval" => parsed.eval = Some(value()?),
+            "--depth" => parsed.depth = value()?.parse().map_err(|_| "Invalid depth")?,
+            _ if parsed.fens.is_empty() && !arg.starts_with("--") => parsed.fens = arg,
+            _ => return Err(format!("Unknown argument {arg}"))
+        }
+    }
+    if parsed.fens.is_empty() {
+        return Err("No FEN file given".to_string());
+    }
+    Ok(parsed)
+}
+
+fn run(args: Args) -> Result<usize, String> {
+    let params = EvalParams::default();
+    let entries = match &args.eval {
+        Some(name) => vec![find_evaluator(name).ok_or(format!("Unknown evaluator {name}"))?],
+        None => EVALUATORS.iter().collect()
+    };
+    let mut evaluators = Vec::new();
+    for entry in entries {
+        match entry.create(&params) {
+            Ok(evaluator) => evaluators.push((entry.name, evaluator)),
+            // Only an error when it was asked for
+            Err(err) if args.eval.is_some() => return Err(format!("{}: {err}", entry.name)),
+            Err(err) => println!("Skipping {}: {err}", entry.name)
+        }
+    }
+
+    let contents = fs::read_to_string(&args.fens).map_err(|err| format!("Could not read {}: {err}", args.fens))?;
+    let (mut positions, mut moves, mut failures) = (0, 0, 0);
+    let mut fail = |line: usize, fen: &str, err: String| {
+        println!("{}:{line}: {fen}: {err}", args.fens);
+        failures += 1;
+    };
+    for (i, fen) in contents.lines().map(str::trim).enumerate().filter(|(_, fen)| !fen.is_empty() && !fen.starts_with('#')) {
+        let data = match parse_fen(fen) {
+            Ok(data) => data,
+            Err(err) => {
+                fail(i + 1, fen, err.0.unwrap_or("Invalid FEN").to_string());
+                continue;
+            }
+        };
+        positions += 1;
+        for (name, evaluator) in &evaluators {
+            if let Err(err) = check_symmetry(evaluator.as_ref(), &data) {
+                fail(i + 1, fen, format!("{name} {err}"));
+            }
+        }
+        let evaluator = evaluators.first().map_or_else(default_evaluator, |(_, evaluator)| evaluator.clone());
+        match check_make_unmake(fen, evaluator, args.depth) {
+            Ok(count) => moves += count,
+            Err(err) => fail(i + 1, fen, err)
+        }
+    }
+    println!("Checked {positions} positions and {moves} moves, {failures} failures");
+    Ok(failures)
+}
+
+fn main() {
+    let args = parse_args().unwrap_or_else(|err| {
+        eprintln!("{err}\n\n{USAGE}");
+        process::exit(1);
+    });
+    match run(args) {
+        Ok(0) => {},
+        Ok(_) => process::exit(1),
+        Err(err) => {
+            eprintln!("{err}");
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use rust_chess::tuner::{Tuner, load_positions};
+use rust_chess::score_functions::EvalParams;
+
+use std::{env, fs, process};
+
+const USAGE: &str = "Usage: tune <positions> [--out <file>] [--params <file>] [--step <n>] [--passes <n>]
+
+Tunes the tapered evaluation on positions labelled with game results, one \"<fen> <result>\"
+per line. The result is 1-0, 0-1, 1/2-1/2 or a number from 0 to 1 from white's point of view.
+The tuned parameters are written as JSON to --out (default eval_params.json) after every pass.
+--params starts from a JSON or TOML file instead of the built in values. The step size
+starts at --step (default 4) and is halved whenever a pass doesn't improve the error.";
+
+struct Args {
+    positions: String,
+    out: String,
+    params: Option<String>,
+    step: i32,
+    passes: usize
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = env::args().skip(1);
+    let mut parsed = Args { positions: String::new(), out: "eval_params.json".to_string(), params: None, step: 4, passes: usize::MAX };
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {arg}"));
+        match arg.as_str() {
+            "--out" => parsed.out = value()?,
+            "--params" => parsed.params = Some(value()?),
+            "--step" => parsed.step = value()?.parse().map_err(|_| "Invalid step")?,
+            "--passes" => parsed.passes = value()?.parse().map_err(|_| "Invalid number of passes")?,
+            _ if parsed.positions.is_empty() && !arg.starts_with("--") => parsed.positions = arg,
+            _ => return Err(format!("Unknown argument {arg}"))
+        }
+    }
+    if parsed.positions.is_empty() {
+        return Err("No positions given".to_string());
+    }
+    Ok(parsed)
+}
+
+fn run(args: Args) -> Result<(), String> {
+    let params = match &args.params {
+        Some(path) => EvalParams::from_file(path)?,
+        None => EvalParams::default()
+    };
+    let positions = load_positions(&args.positions)?;
+    println!("Loaded {} positions", positions.len());
+
+    let mut tuner = Tuner::new(positions, params);
+    println!("Fitted K = {:.4}, error {:.6}", tuner.k(), tuner.error());
+    let mut step = args.step.max(1);
+    for pass in 1..=args.passes {
+        let improved = tuner.pass(step);
+        println!("Pass {pass}: step {step}, error {:.6}", tuner.error());
+        let json = serde_json::to_string_pretty(tuner.params()).map_err(|err| err.to_string())?;
+        fs::write(&args.out, json).map_err(|err| format!("Could not write {}: {err}", args.out))?;
+        if !improved {
+            if step == 1 {
+                break;
+            }
+            step /= 2;
+        }
+    }
+    println!("Wrote tuned parameters to {}", args.out);
+    Ok(())
+}
+
+fn main() {
+    let args = parse_args().unwrap_or_else(|err| {
+        eprintln!("{err}\n\n{USAGE}");
+        process::exit(1);
+    });
+    if let Err(err) = run(args) {
+        eprintln!("{err}");
+        process::exit(1);
+    }
+}
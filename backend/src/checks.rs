@@ -0,0 +1,86 @@
+use crate::types::*;
+use crate::engines::First;
+use crate::game_state::GameState;
+use crate::score_functions::Evaluator;
+
+use itertools::Itertools;
+use std::sync::Arc;
+
+/*
+*   Consistency checks that can be run over a corpus of positions, see the `check` binary.
+*   A failed check describes what went wrong.
+*/
+
+// Swapping the colours and mirroring the board has to negate the evaluation
+pub fn check_symmetry(evaluator: &dyn Evaluator, data: &FenData) -> Result<(), String> {
+    let flipped = data.flipped();
+    let score = evaluator.evaluate(&data.board, data.player);
+    let flipped_score = evaluator.evaluate(&flipped.board, flipped.player);
+    if score == -flipped_score {
+        return Ok(());
+    }
+    // Point out the terms that differ, for evaluators that can break their score down
+    let trace = evaluator.trace(&data.board, data.player);
+    let flipped_trace = evaluator.trace(&flipped.board, flipped.player);
+    let terms = trace.terms.iter()
+        .zip(&flipped_trace.terms)
+        .filter(|(term, flipped)| term.white != flipped.black || term.black != flipped.white)
+        .map(|(term, _)| term.name)
+        .join(", ");
+    let terms = if terms.is_empty() {String::new()} else {format!(" (differs in {terms})")};
+    Err(format!("evaluates to {score} but the flipped position to {flipped_score}{terms}"))
+}
+
+// Make and unmake all moves up to `depth` plies, returning the number of moves checked.
+// The positions after every move are compared with those of the game state, which plays moves independently of the engine.
+pub fn check_make_unmake(fen: &str, evaluator: Arc<dyn Evaluator>, depth: usize) -> Result<usize, String> {
+    let reference = GameState::from_fen(fen)?;
+    let mut engine = First::new();
+    engine.set_position(fen).map_err(|err| err.0.unwrap_or("Invalid FEN").to_string())?;
+    engine.set_evaluator(evaluator);
+    engine.check_make_unmake(depth, &reference)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::score_functions::{EvalParams, EVALUATORS};
+    use crate::utils::parse_fen;
+
+    // Lopsided positions, with castling rights and an en passant square on one side only
+    const FENS: &[&str] = &[
+        "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+        "rnbqkbnr/ppp1p1pp/8/3pPp2/8/8/PPPP1PPP/RNBQKBNR w KQkq f6 0 3",
+        "r3k3/1p3ppp/8/8/2B5/8/PPP2PPP/4K2R b Kq - 0 1",
+        "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+        "6k1/5ppp/8/8/8/8/1Q6/K7 b - - 0 1"
+    ];
+
+    fn evaluators() -> Vec<Arc<dyn Evaluator>> {
+        // nnue is only there when its weights are
+        EVALUATORS.iter().filter_map(|entry| entry.create(&EvalParams::default()).ok()).collect()
+    }
+
+    #[test]
+    fn flipping_negates_the_evaluation() {
+        for evaluator in evaluators() {
+            for fen in FENS {
+                let data = parse_fen(fen).unwrap();
+                let flipped = data.flipped();
+                let score = evaluator.evaluate(&data.board, data.player);
+                assert_eq!(evaluator.evaluate(&flipped.board, flipped.player), -score, "{fen}");
+                assert_eq!(check_symmetry(evaluator.as_ref(), &data), Ok(()), "{fen}");
+            }
+        }
+    }
+
+    #[test]
+    fn make_unmake_restores_positions() {
+        for evaluator in evaluators() {
+            for fen in FENS {
+                let moves = check_make_unmake(fen, evaluator.clone(), 2);
+                assert!(moves.as_ref().is_ok_and(|&moves| moves > 0), "{fen}: {moves:?}");
+            }
+        }
+    }
+}
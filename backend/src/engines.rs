@@ -6,17 +6,172 @@ pub use first::First;
 pub use first_par::FirstPar;
 pub use first_trans::FirstTrans;
 
-use crate::types::{Piece, Position, CastleStatus};
+use crate::types::*;
+use crate::skill::Skill;
+use crate::score_functions::{Evaluator, default_evaluator};
+use serde::Serialize;
+use std::sync::Arc;
 
 pub struct MoveStatus {
     piece: Piece,
     capture: Piece,
     en_passant: Option<Position>,
-    castle_status: Option<CastleStatus>
+    castle_status: Option<CastleStatus>,
+    last_capture: Option<Position>,
+    hash: u64
 }
 
 impl MoveStatus {
     fn default() -> MoveStatus {
-        MoveStatus { piece: 0, capture: 0, en_passant: None, castle_status: None }
+        MoveStatus { piece: 0, capture: 0, en_passant: None, castle_status: None, last_capture: None, hash: 0 }
     }
-}
\ No newline at end of file
+}
+
+// Nodes searched between reports to the shared stop signal, which also checks the node and time limits
+const NODE_CHECK_INTERVAL: usize = 1024;
+
+// A pawn moving diagonally to an empty square can only be taking en passant
+fn is_en_passant(piece: Piece, (from, to): Move, capture: Piece) -> bool {
+    piece.is(PAWN) && from.0 != to.0 && capture == 0
+}
+
+// Settings that shape how an engine plays, as opposed to how long it searches. Engines ignore the ones they don't support.
+#[derive(Clone, Debug)]
+pub struct EngineOptions {
+    pub seed: Option<u64>,
+    pub skill: Option<Skill>,
+    pub evaluator: Arc<dyn Evaluator>
+}
+
+impl Default for EngineOptions {
+    fn default() -> EngineOptions {
+        EngineOptions { seed: None, skill: None, evaluator: default_evaluator() }
+    }
+}
+
+type Constructor = fn(&EngineOptions) -> Box<dyn Engine + Send>;
+
+#[derive(Serialize)]
+pub struct EngineEntry {
+    pub name: &'static str,
+    pub description: &'static str,
+    // Request parameters that the engine makes use of
+    pub params: &'static [&'static str],
+    #[serde(skip)]
+    create: Constructor
+}
+
+impl EngineEntry {
+    pub fn create(&self, options: &EngineOptions) -> Box<dyn Engine + Send> {
+        (self.create)(options)
+    }
+}
+
+pub const DEFAULT_ENGINE: &str = "first_par";
+
+pub static ENGINES: &[EngineEntry] = &[
+    EngineEntry {
+        name: "first_par",
+        description: "Alpha-beta search on several threads sharing a transposition table",
+        params: &["depth", "threads", "nodes", "movetime", "multipv", "seed", "skill", "elo", "eval", "params", "id"],
+        create: create_first_par
+    },
+    EngineEntry {
+        name: "first",
+        description: "Alpha-beta search on a single thread",
+        params: &["depth", "nodes", "movetime", "multipv", "seed", "eval", "params", "id"],
+        create: create_first
+    },
+    EngineEntry {
+        name: "first_trans",
+        description: "Plain alpha-beta search that caches the score of every position it reaches",
+        params: &["depth", "nodes", "movetime", "seed", "eval", "params", "id"],
+        create: create_first_trans
+    }
+];
+
+pub fn find_engine(name: &str) -> Option<&'static EngineEntry> {
+    ENGINES.iter().find(|entry| entry.name == name)
+}
+
+fn create_first_par(options: &EngineOptions) -> Box<dyn Engine + Send> {
+    let mut engine = FirstPar::new();
+    engine.set_seed(options.seed);
+    engine.set_evaluator(options.evaluator.clone());
+    if let Some(skill) = options.skill {
+        engine.set_skill(skill);
+    }
+    Box::new(engine)
+}
+
+fn create_first(options: &EngineOptions) -> Box<dyn Engine + Send> {
+    let mut engine = First::new();
+    engine.set_seed(options.seed);
+    engine.set_evaluator(options.evaluator.clone());
+    Box::new(engine)
+}
+
+fn create_first_trans(options: &EngineOptions) -> Box<dyn Engine + Send> {
+    let mut engine = FirstTrans::new();
+    engine.set_seed(options.seed);
+    engine.set_evaluator(options.evaluator.clone());
+    Box::new(engine)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::mate_in;
+    use std::time::Duration;
+
+    fn search(entry: &EngineEntry, fen: &str, depth: usize) -> (Option<Move>, Score) {
+        let mut engine = entry.create(&EngineOptions::default());
+        engine.set_position(fen).unwrap();
+        engine.make_move(&SearchLimits { depth, ..SearchLimits::default() })
+    }
+
+    #[test]
+    fn mate_scores_count_plies() {
+        // The mate is only seen once the search looks past the mating move
+        for entry in ENGINES {
+            for depth in 2..=5 {
+                let (mv, score) = search(entry, "6k1/5ppp/8/8/8/8/5PPP/3R2K1 w - - 0 1", depth);
+                assert_eq!(mv, Some(((3, 0), (3, 7))), "{} at depth {depth}", entry.name);
+                assert_eq!(mate_in(score), Some(1), "{} at depth {depth}", entry.name);
+                let (mv, score) = search(entry, "3r2k1/5ppp/8/8/8/8/5PPP/6K1 b - - 0 1", depth);
+                assert_eq!(mv, Some(((3, 7), (3, 0))), "{} at depth {depth}", entry.name);
+                assert_eq!(mate_in(score), Some(-1), "{} at depth {depth}", entry.name);
+            }
+        }
+    }
+
+    #[test]
+    fn limits_end_every_search() {
+        for entry in ENGINES {
+            let mut engine = entry.create(&EngineOptions::default());
+            let start = std::time::Instant::now();
+            let (mv, _) = engine.make_move(&SearchLimits { depth: 64, movetime: Some(Duration::from_millis(200)), ..SearchLimits::default() });
+            assert!(mv.is_some() && start.elapsed() < Duration::from_secs(5), "{} with movetime", entry.name);
+
+            let (mv, _) = engine.make_move(&SearchLimits { depth: 64, nodes: Some(20_000), ..SearchLimits::default() });
+            assert!(mv.is_some() && engine.stats().nodes < 1_000_000, "{} with a node limit", entry.name);
+
+            // Raised before the search starts, only the first iteration can't be cut short
+            let stop = SearchStop::default();
+            stop.stop();
+            let (mv, _) = engine.make_move(&SearchLimits { depth: 64, stop, ..SearchLimits::default() });
+            assert!(mv.is_some(), "{} stopped", entry.name);
+            assert_eq!(engine.stats().depth, 1, "{} stopped", entry.name);
+        }
+    }
+
+    #[test]
+    fn no_legal_moves_at_the_root() {
+        for entry in ENGINES {
+            let (mv, score) = search(entry, "R5k1/5ppp/8/8/8/8/5PPP/6K1 b - - 0 1", 3);
+            assert_eq!((mv, mate_in(score)), (None, Some(0)), "{}", entry.name);
+            let (mv, score) = search(entry, "7k/5Q2/6K1/8/8/8/8/8 b - - 0 1", 3);
+            assert_eq!((mv, score), (None, 0), "{}", entry.name);
+        }
+    }
+}
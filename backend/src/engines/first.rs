@@ -1,68 +1,125 @@
 use crate::types::*;
 use crate::utils::*;
-use super::MoveStatus;
+use crate::zobrist::*;
+use crate::trans_table::*;
+use crate::game_state::GameState;
+use super::{MoveStatus, NODE_CHECK_INTERVAL, is_en_passant};
 
 use itertools::Itertools;
-use crate::break_block;
 use crate::score_functions::*;
+use std::sync::Arc;
+use std::time::Instant;
+use log::{debug, error};
+
+const NULL_MOVE_REDUCTION: usize = 2;
+const NULL_MOVE_MIN_DEPTH: usize = 3;
+const LMR_MIN_DEPTH: usize = 3;
+const LMR_MIN_MOVES: usize = 3;
+const FUTILITY_MARGIN: Score = 300;
+const ASPIRATION_WINDOW: Score = 50;
+const TRANS_TABLE_MB: usize = 16;
+const SINGULAR_MIN_DEPTH: usize = 4;
+const SINGULAR_MARGIN: Score = 20;
 
 #[derive(Clone, Debug)]
 pub struct First {
-    board: Board,
-    player: Player,
+    pub(super) board: Board,
+    pub(super) player: Player,
     can_castle: CastleStatus,
     en_passant: Option<Position>,
     depth: usize,
-    pub num_leafs: usize
+    root_depth: usize,
+    ply: usize,
+    hash: u64,
+    last_capture: Option<Position>,
+    excluded_move: Option<Move>,
+    trans_table: Arc<TransTable>,
+    // Quiet moves that caused cutoffs, by side, from and to square. Kept between searches.
+    history: Vec<Score>,
+    stop: SearchStop,
+    report: Option<SearchReport>,
+    unreported_nodes: usize,
+    multi_pv: usize,
+    pv_table: Vec<Vec<Move>>,
+    lines: Vec<PvLine>,
+    stats: SearchStats,
+    seed: Option<u64>,
+    evaluator: Arc<dyn Evaluator>,
+    accumulator: Option<Box<dyn Accumulator>>,
+    eval_noise: Score,
+    noise_seed: u64
 }
 
 impl Engine for First {
-    fn new(fen: &str, depth: usize) -> Result<Self, BadRequest> {
-        let fen_data = parse_fen(fen)?;
-    
-        Ok(First {
+    fn new() -> Self {
+        let fen_data = parse_fen(START_FEN).unwrap();
+        First {
+            hash: hash_position(&fen_data.board, fen_data.player, &fen_data.can_castle, fen_data.en_passant),
             board: fen_data.board,
             player: fen_data.player,
             can_castle: fen_data.can_castle,
             en_passant: fen_data.en_passant,
-            depth,
-            num_leafs: 0
-        })
+            depth: DEFAULT_DEPTH,
+            root_depth: DEFAULT_DEPTH,
+            ply: 0,
+            last_capture: None,
+            excluded_move: None,
+            trans_table: Arc::new(TransTable::new(TRANS_TABLE_MB)),
+            history: vec![0; 2 * 64 * 64],
+            stop: SearchStop::default(),
+            report: None,
+            unreported_nodes: 0,
+            multi_pv: 1,
+            pv_table: Vec::new(),
+            lines: Vec::new(),
+            stats: SearchStats::default(),
+            seed: None,
+            evaluator: default_evaluator(),
+            accumulator: None,
+            eval_noise: 0,
+            noise_seed: 0
+        }
     }
 
-    fn get_best_moves(&mut self) -> (Vec<Move>, Score) {
-        let mut best_moves = Vec::new();
-        let mut best = Score::NEG_INFINITY * self.player as Score;
-        let valid_moves = self.board.get_valid(self.player, &self.can_castle, self.en_passant);
-        println!("Number of initial moves: {}", valid_moves.len());
-        println!("Progress: [{}]", (0..valid_moves.len()).map(|_| "-").collect::<String>());
-        print!("           ");
-        for mv in valid_moves.into_iter().sorted_unstable_by_key(|&(_, to)| -self.board[to].value_unsigned() as isize) {
-            print!("#");
-            let move_status = self.do_move(mv);
-            let score = self.recursive_best(Score::NEG_INFINITY, Score::INFINITY);
-            self.undo_move(mv, move_status);
-            if score.is_none() {
-                println!("Previous error occured after move: {:?} -> {:?}", mv.0, mv.1);
-                panic!("Panicing due to previous error!");
-            }
-            let score = score.unwrap();
-            if self.is_white() && score >= best {
-                if score > best {
-                    best_moves = Vec::new();
-                    best = score
-                }
-                best_moves.push(mv);
-            } else if !self.is_white() && score <= best {
-                if score < best {
-                    best_moves = Vec::new();
-                    best = score
-                }
-                best_moves.push(mv);
-            }
+    fn set_position(&mut self, fen: &str) -> Result<(), BadRequest> {
+        let fen_data = parse_fen(fen)?;
+        self.hash = hash_position(&fen_data.board, fen_data.player, &fen_data.can_castle, fen_data.en_passant);
+        self.board = fen_data.board;
+        self.player = fen_data.player;
+        self.can_castle = fen_data.can_castle;
+        self.en_passant = fen_data.en_passant;
+        self.last_capture = None;
+        self.lines.clear();
+        if let Some(accumulator) = &mut self.accumulator {
+            accumulator.reset(&self.board);
         }
-        println!();
-        (best_moves, best)
+        Ok(())
+    }
+
+    fn get_best_moves(&mut self, limits: &SearchLimits) -> (Vec<Move>, Score) {
+        self.start_search(limits, limits.stop.with_limits(limits.nodes, limits.movetime));
+        self.search()
+    }
+
+    fn stats(&self) -> SearchStats {
+        self.stats.clone()
+    }
+
+    fn lines(&self) -> &[PvLine] {
+        &self.lines
+    }
+
+    fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    fn set_seed(&mut self, seed: Option<u64>) {
+        self.seed = seed;
+    }
+
+    fn set_evaluator(&mut self, evaluator: Arc<dyn Evaluator>) {
+        self.accumulator = evaluator.new_accumulator(&self.board);
+        self.evaluator = evaluator;
     }
 }
 
@@ -73,15 +130,137 @@ impl First {
         self.player == 1
     }
 
+    // Set up a search with the given limits. Threads searching together share the stop signal.
+    pub(super) fn start_search(&mut self, limits: &SearchLimits, stop: SearchStop) {
+        self.depth = limits.depth;
+        self.multi_pv = limits.multi_pv.max(1);
+        self.stop = stop;
+        self.report = limits.report.clone();
+        self.unreported_nodes = 0;
+        // Older results are less relevant to the new search
+        self.history.iter_mut().for_each(|score| *score /= 2);
+    }
+
+    // Search the position as set up by start_search
+    pub(super) fn search(&mut self) -> (Vec<Move>, Score) {
+        let root_moves = self.root_moves();
+        debug!("Number of initial moves: {}", root_moves.len());
+        self.iterative_deepening(root_moves, true)
+    }
+
+    // Add an error of up to `amplitude` centipawns to every evaluation. The error is derived from
+    // the position hash, so a position gets the same evaluation each time it is searched.
+    pub fn set_eval_noise(&mut self, amplitude: Score, seed: u64) {
+        self.eval_noise = amplitude.max(0);
+        self.noise_seed = seed;
+    }
+
+    fn history_index(&self, (from, to): Move) -> usize {
+        (self.is_white() as usize * 64 + from.1 * 8 + from.0) * 64 + to.1 * 8 + to.0
+    }
+
+    // Node counts are reported to the shared stop signal in batches to keep the threads from contending on it
+    fn count_node(&mut self) {
+        self.stats.nodes += 1;
+        self.stats.seldepth = self.stats.seldepth.max(self.ply);
+        self.unreported_nodes += 1;
+        if self.unreported_nodes == NODE_CHECK_INTERVAL {
+            self.stop.add_nodes(NODE_CHECK_INTERVAL);
+            self.unreported_nodes = 0;
+        }
+    }
+
+    // Lazy SMP helper thread. Runs the same search as the main thread, but with the root moves
+    // in a different order and every other helper one ply deeper, so that the threads fill the
+    // shared transposition table with different parts of the tree.
+    pub(super) fn search_helper(&mut self, id: usize) {
+        self.depth += id % 2;
+        let mut root_moves = self.root_moves();
+        let shift = id % root_moves.len().max(1);
+        root_moves.rotate_left(shift);
+        self.iterative_deepening(root_moves, false);
+    }
+
+    fn root_moves(&mut self) -> Vec<Move> {
+        self.board.get_valid(self.player, &self.can_castle, self.en_passant)
+            .into_iter()
+            .sorted_unstable_by_key(|&(_, to)| -self.board[to].value_unsigned() as isize)
+            .collect()
+    }
+
+    // Search one ply deeper at a time until the target depth is reached or the search is stopped,
+    // returning the result of the deepest completed iteration
+    fn iterative_deepening(&mut self, mut root_moves: Vec<Move>, verbose: bool) -> (Vec<Move>, Score) {
+        let start = Instant::now();
+        self.stats = SearchStats::default();
+        self.lines.clear();
+        if root_moves.is_empty() {
+            let score = if self.in_check() {-SCORE_MATE * self.player as Score} else {0};
+            return (Vec::new(), score);
+        }
+        let max_depth = self.depth;
+        let mut best_moves = Vec::new();
+        let mut best = -SCORE_INF * self.player as Score;
+        let mut prev_iteration_nodes = 0;
+        for depth in 1..=max_depth {
+            self.depth = depth;
+            self.root_depth = depth;
+            let nodes_before = self.stats.nodes;
+            let lines = match self.aspiration_search(&root_moves, (depth > 1).then_some(best)) {
+                Some(lines) => lines,
+                None => break
+            };
+            best = lines[0].score;
+            best_moves = lines.iter().take_while(|line| line.score == best).map(|line| line.moves[0]).collect();
+            let iteration_nodes = self.stats.nodes - nodes_before;
+            if prev_iteration_nodes > 0 {
+                self.stats.branching_factor = iteration_nodes as f64 / prev_iteration_nodes as f64;
+            }
+            prev_iteration_nodes = iteration_nodes;
+            self.stats.depth = depth;
+            if verbose {
+                debug!("Depth {depth}: score {best}, nodes {}, best moves: {best_moves:?}", self.stats.nodes);
+            }
+            // Search the moves in the order of this iteration's scores in the next one
+            root_moves = lines.iter().map(|line| line.moves[0]).collect();
+            self.lines = lines;
+            self.lines.truncate(self.multi_pv);
+            if let Some(report) = self.report.as_ref().filter(|_| verbose) {
+                report.send(&SearchProgress {
+                    depth,
+                    seldepth: self.stats.seldepth,
+                    nodes: self.stop.nodes() + self.unreported_nodes,
+                    time: start.elapsed(),
+                    lines: &self.lines
+                });
+            }
+        }
+        self.depth = max_depth;
+        self.stats.set_time(start.elapsed());
+        (best_moves, best)
+    }
+
     fn do_move(&mut self, (from, to): Move) -> MoveStatus {
         let mut status = MoveStatus::default();
         status.capture = self.board[to];
+        status.hash = self.hash;
+        status.last_capture = self.last_capture;
+        self.last_capture = if status.capture != 0 {Some(to)} else {None};
         let piece = self.board[from];
+        self.hash ^= piece_key(piece, from) ^ piece_key(status.capture, to) ^ castle_key(&self.can_castle) ^ en_passant_key(self.en_passant);
         self.board[to] = piece;
         self.board[from] = 0;
         status.piece = piece;
         let player = self.player;
 
+        // En passant, the captured pawn is beside the pawn that takes it
+        let en_passant_capture = is_en_passant(piece, (from, to), status.capture).then_some((to.0, from.1));
+        if let Some(pos) = en_passant_capture {
+            self.board[pos] = 0;
+            self.hash ^= piece_key(-PAWN * player, pos);
+            self.last_capture = Some(to);
+        }
+
         // Reset en passant square and store previous value in status
         if let Some(en_passant) = self.en_passant {
             status.en_passant = Some(en_passant);
@@ -123,16 +302,53 @@ impl First {
                     // King side
                     self.board[from.1][from.0+1] = ROOK * player;
                     self.board[from.1][7] = 0;
+                    self.hash ^= piece_key(ROOK * player, (from.0+1, from.1)) ^ piece_key(ROOK * player, (7, from.1));
                 } else if from.0 == to.0 + 2 {
                     // Queen side
                     self.board[from.1][from.0-1] = ROOK * player;
                     self.board[from.1][0] = 0;
+                    self.hash ^= piece_key(ROOK * player, (from.0-1, from.1)) ^ piece_key(ROOK * player, (0, from.1));
                 }
             },
             _ => {}
         }
 
+        // Capturing a rook in its corner ends castling on that side
+        if status.capture.is(ROOK) && to.1 == (-player).back_rank() {
+            let rights = &self.can_castle[-player];
+            if (to.0 == 7 && rights.k) || (to.0 == 0 && rights.q) {
+                status.castle_status.get_or_insert_with(|| self.can_castle.clone());
+                if to.0 == 7 {
+                    self.can_castle[-player].k = false;
+                } else {
+                    self.can_castle[-player].q = false;
+                }
+            }
+        }
+
+        if let Some(accumulator) = &mut self.accumulator {
+            let mut changes = [
+                SquareChange { pos: from, old: piece, new: 0 },
+                SquareChange { pos: to, old: status.capture, new: self.board[to] },
+                SquareChange { pos: from, old: 0, new: 0 },
+                SquareChange { pos: from, old: 0, new: 0 }
+            ];
+            let mut count = 2;
+            if let Some(pos) = en_passant_capture {
+                changes[2] = SquareChange { pos, old: -PAWN * player, new: 0 };
+                count = 3;
+            } else if piece.is(KING) && from.0.abs_diff(to.0) == 2 {
+                let (rook_from, rook_to) = if from.0 < to.0 {(7, from.0 + 1)} else {(0, from.0 - 1)};
+                changes[2] = SquareChange { pos: (rook_from, from.1), old: ROOK * player, new: 0 };
+                changes[3] = SquareChange { pos: (rook_to, from.1), old: 0, new: ROOK * player };
+                count = 4;
+            }
+            accumulator.push(&self.board, &changes[..count]);
+        }
+
+        self.hash ^= piece_key(self.board[to], to) ^ castle_key(&self.can_castle) ^ en_passant_key(self.en_passant) ^ player_key(player) ^ player_key(-player);
         self.depth -= 1;
+        self.ply += 1;
         self.player *= -1;
         status
 
@@ -142,12 +358,21 @@ impl First {
         self.board[from] = status.piece;
         self.board[to] = status.capture;
         self.en_passant = status.en_passant;
+        self.hash = status.hash;
+        self.last_capture = status.last_capture;
         if let Some(castle_status) = status.castle_status {
             self.can_castle = castle_status;
         }
 
         self.player *= -1;
         self.depth += 1;
+        self.ply -= 1;
+        if let Some(accumulator) = &mut self.accumulator {
+            accumulator.pop();
+        }
+        if is_en_passant(status.piece, (from, to), status.capture) {
+            self.board[(to.0, from.1)] = -PAWN * self.player;
+        }
 
         // Handle castle
         if status.piece.is(KING) && from.0.abs_diff(to.0) == 2 {
@@ -165,54 +390,460 @@ impl First {
         
     }
 
+    /*
+    *   Make and unmake every sequence of moves up to `depth` plies from the current position.
+    *   After every move the position has to match the one `reference`, the same game state,
+    *   gets to by playing the move. The hash has to match one computed from scratch, and an
+    *   evaluator that is updated move by move has to agree with a full evaluation. Unmaking the
+    *   move has to restore the board, side to move, castling rights, en passant square and hash.
+    *   Returns the number of moves checked.
+    */
+    pub fn check_make_unmake(&mut self, depth: usize, reference: &GameState) -> Result<usize, String> {
+        self.depth = depth;
+        self.check_moves(depth, &mut Vec::new(), reference)
+    }
+
+    // The parts of the position that differ from the given ones, empty if none do
+    fn differences(&self, board: &Board, player: Player, can_castle: &CastleStatus, en_passant: Option<Position>) -> String {
+        let differences = [
+            ("board", self.board != *board),
+            ("side to move", self.player != player),
+            ("castling rights", self.can_castle != *can_castle),
+            ("en passant square", self.en_passant != en_passant)
+        ];
+        differences.iter().filter(|(_, differs)| *differs).map(|(name, _)| name).join(", ")
+    }
+
+    fn check_moves(&mut self, depth: usize, line: &mut Vec<Move>, reference: &GameState) -> Result<usize, String> {
+        if depth == 0 {
+            return Ok(0);
+        }
+        let mut count = 0;
+        for mv in self.board.get_valid(self.player, &self.can_castle, self.en_passant) {
+            let before = (self.board.clone(), self.player, self.can_castle.clone(), self.en_passant, self.hash);
+            line.push(mv);
+            let describe = |line: &[Move]| line.iter().copied().map(move_to_string).join(" ");
+            let status = self.do_move(mv);
+            let mut expected = reference.clone();
+            expected.make_move(mv, None);
+            let position = expected.position();
+            let differences = self.differences(&position.board, position.player, &position.can_castle, position.en_passant);
+            if !differences.is_empty() {
+                return Err(format!("{}: do_move differs from the game state in the {differences}", describe(line)));
+            }
+            if self.hash != hash_position(&self.board, self.player, &self.can_castle, self.en_passant) {
+                return Err(format!("{}: the hash differs from a full hash", describe(line)));
+            }
+            if let Some(accumulator) = &self.accumulator {
+                let incremental = accumulator.evaluate(&self.board, self.player);
+                let full = self.evaluator.evaluate(&self.board, self.player);
+                if incremental != full {
+                    return Err(format!("{}: incremental evaluation {incremental} differs from full evaluation {full}", describe(line)));
+                }
+            }
+            count += 1 + self.check_moves(depth - 1, line, &expected)?;
+            self.undo_move(mv, status);
+
+            let (board, player, can_castle, en_passant, hash) = before;
+            let mut differences = self.differences(&board, player, &can_castle, en_passant);
+            if self.hash != hash {
+                differences += if differences.is_empty() {"hash"} else {", hash"};
+            }
+            if !differences.is_empty() {
+                return Err(format!("{}: undo_move did not restore the {differences}", describe(line)));
+            }
+            line.pop();
+        }
+        Ok(count)
+    }
+
     fn score_function(&self) -> Score {
-        score1(&self.board, -self.player)
+        let score = match &self.accumulator {
+            Some(accumulator) => accumulator.evaluate(&self.board, self.player),
+            None => self.evaluator.evaluate(&self.board, self.player)
+        };
+        score + self.noise()
     }
 
-    fn recursive_best(&mut self, mut alpha: Score, mut beta: Score) -> Option<Score> {
+    fn noise(&self) -> Score {
+        if self.eval_noise == 0 {
+            return 0;
+        }
+        let mixed = (self.hash ^ self.noise_seed).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32;
+        (mixed % (2 * self.eval_noise as u64 + 1)) as Score - self.eval_noise
+    }
+
+    // Search the root with a narrow window around the previous iteration's score,
+    // falling back to an open bound on the side that failed. With several lines
+    // requested the scores are spread out too far for a narrow window to pay off.
+    fn aspiration_search(&mut self, root_moves: &[Move], prev: Option<Score>) -> Option<Vec<PvLine>> {
+        let (mut alpha, mut beta) = match prev.filter(|score| self.multi_pv == 1 && score.abs() <= SCORE_MATE_BOUND) {
+            Some(score) => (score - ASPIRATION_WINDOW, score + ASPIRATION_WINDOW),
+            None => (-SCORE_INF, SCORE_INF)
+        };
+        loop {
+            let lines = self.search_root(root_moves, alpha, beta)?;
+            let best = lines[0].score;
+            if best <= alpha && alpha > -SCORE_INF {
+                alpha = -SCORE_INF;
+            } else if best >= beta && beta < SCORE_INF {
+                beta = SCORE_INF;
+            } else {
+                return Some(lines);
+            }
+        }
+    }
+
+    // Score every root move, returning the lines sorted best first. Only the first `multi_pv`
+    // lines, and any moves tied with the best one, are guaranteed to have exact scores.
+    fn search_root(&mut self, root_moves: &[Move], alpha: Score, beta: Score) -> Option<Vec<PvLine>> {
+        let mut lines: Vec<PvLine> = Vec::new();
+        for &mv in root_moves {
+            // Only moves that can make it into the requested lines, or tie with the last of them, need an exact score
+            let threshold = lines.get(self.multi_pv - 1).map_or(-SCORE_INF * self.player as Score, |line| line.score);
+            let (alpha, beta) = if self.is_white() {
+                (alpha.max(threshold - 1), beta)
+            } else {
+                (alpha, beta.min(threshold + 1))
+            };
+            let move_status = self.do_move(mv);
+            let score = self.recursive_best(alpha, beta, true);
+            let mut moves = vec![mv];
+            moves.extend_from_slice(&self.pv_table[self.ply]);
+            self.undo_move(mv, move_status);
+            if score.is_none() {
+                if self.stop.is_stopped() {
+                    return None;
+                }
+                error!("Previous error occured after move: {:?} -> {:?}", mv.0, mv.1);
+                panic!("Panicing due to previous error!");
+            }
+            let line = PvLine { score: score.unwrap(), moves };
+            // Fail high, the window has to be widened before the moves can be compared
+            if (self.is_white() && line.score >= beta) || (!self.is_white() && line.score <= alpha) {
+                return Some(vec![line]);
+            }
+            // Keep the lines sorted, stable so that ties stay in search order
+            let index = lines.partition_point(|other| if self.is_white() {other.score >= line.score} else {other.score <= line.score});
+            lines.insert(index, line);
+        }
+        Some(lines)
+    }
+
+    fn in_check(&self) -> bool {
+        let king_pos = self.board.get_king_pos(self.player);
+        self.board.is_checked(king_pos, self.player)
+    }
+
+    fn is_promotion(&self, (from, to): Move) -> bool {
+        self.board[from].is(PAWN) && (to.1 == 0 || to.1 == 7)
+    }
+
+    // The principal variation of every node is collected bottom up in pv_table[ply]
+    fn clear_pv(&mut self) {
+        if self.pv_table.len() <= self.ply {
+            self.pv_table.resize(self.ply + 1, Vec::new());
+        }
+        self.pv_table[self.ply].clear();
+    }
+
+    fn update_pv(&mut self, mv: Move) {
+        let (head, tail) = self.pv_table.split_at_mut(self.ply + 1);
+        let pv = &mut head[self.ply];
+        pv.clear();
+        pv.push(mv);
+        pv.extend_from_slice(&tail[0]);
+    }
+
+    // Singular extension test: search every move except the one from the transposition table
+    // at reduced depth. If none of them come close to its score, the move is the only good one.
+    fn is_singular(&mut self, mv: Move, score: Score, depth: usize) -> Option<bool> {
+        let margin = SINGULAR_MARGIN * depth as Score;
+        let singular_beta = score - margin * self.player as Score;
+        let (alpha, beta) = if self.is_white() {(singular_beta - 1, singular_beta)} else {(singular_beta, singular_beta + 1)};
+        self.excluded_move = Some(mv);
+        self.depth = depth / 2;
+        let result = self.recursive_best(alpha, beta, false);
+        self.depth = depth;
+        self.clear_pv();
+        let result = result?;
+        Some(if self.is_white() {result < singular_beta} else {result > singular_beta})
+    }
+
+    // Pass the turn to the opponent. Used by null move pruning.
+    fn do_null_move(&mut self) -> (Option<Position>, Option<Position>) {
+        let en_passant = self.en_passant.take();
+        let last_capture = self.last_capture.take();
+        self.hash ^= en_passant_key(en_passant) ^ player_key(self.player) ^ player_key(-self.player);
+        self.depth -= 1 + NULL_MOVE_REDUCTION;
+        self.ply += 1;
+        self.player *= -1;
+        (en_passant, last_capture)
+    }
+
+    fn undo_null_move(&mut self, (en_passant, last_capture): (Option<Position>, Option<Position>)) {
+        self.en_passant = en_passant;
+        self.last_capture = last_capture;
+        self.hash ^= en_passant_key(en_passant) ^ player_key(self.player) ^ player_key(-self.player);
+        self.depth += 1 + NULL_MOVE_REDUCTION;
+        self.ply -= 1;
+        self.player *= -1;
+    }
+
+    fn recursive_best(&mut self, mut alpha: Score, mut beta: Score, allow_null: bool) -> Option<Score> {
+        self.count_node();
+        self.clear_pv();
+        // Set by is_singular for the node it is called from only
+        let excluded_move = self.excluded_move.take();
         if self.depth == 0 {
-            self.num_leafs += 1;
+            self.stats.leaf_nodes += 1;
             return Some(self.score_function());
         }
-        let valid_moves = self.board.get_valid(self.player, &self.can_castle, self.en_passant);
-        let mut best = -100.0 * self.player as Score;
-        let king_pos = self.board.get_king_pos(self.player);
+        // Leaves are always evaluated, so that the first iteration can't be aborted and there is always a move to return
+        if self.stop.is_stopped() {
+            return None;
+        }
+
+        // The singular search shares the hash with the full search of the node, so it can't use the table
+        let entry = self.trans_table.probe(self.hash, self.ply).filter(|_| excluded_move.is_none());
+        if entry.is_some() {
+            self.stats.tt_hits += 1;
+        }
+        if let Some(entry) = entry.filter(|entry| entry.depth >= self.depth) {
+            let cutoff = match entry.bound {
+                Bound::Exact => true,
+                Bound::Lower => entry.score >= beta,
+                Bound::Upper => entry.score <= alpha
+            };
+            if cutoff {
+                self.stats.tt_cutoffs += 1;
+                return Some(entry.score);
+            }
+        }
+        let (alpha_orig, beta_orig) = (alpha, beta);
+
+        let mut valid_moves = self.board.get_valid(self.player, &self.can_castle, self.en_passant);
+        let mut best = -SCORE_INF * self.player as Score;
+        let mut best_move = None;
+        let in_check = self.in_check();
         if valid_moves.is_empty() {
-            if self.board.is_checked(king_pos, self.player) {
-                return Some(best - self.depth as Score * self.player as Score);
+            if in_check {
+                return Some(-(SCORE_MATE - self.ply as Score) * self.player as Score);
+            }
+            return Some(0);
+        }
+        if let Some(excluded_move) = excluded_move {
+            valid_moves.retain(|&mv| mv != excluded_move);
+            if valid_moves.is_empty() {
+                return Some(best);
             }
-            return Some(0.0);
-        }  
-        for mv in valid_moves.into_iter().sorted_unstable_by_key(|&(_, to)| -self.board[to].value_unsigned() as isize) {
+        }
+
+        let depth = self.depth;
+        let white = self.is_white();
+
+        // Null move pruning: if passing the turn still fails high, a real move will too.
+        // Skipped in pawn endings where zugzwang makes passing the best "move".
+        if allow_null && excluded_move.is_none() && !in_check && depth >= NULL_MOVE_MIN_DEPTH && self.board.has_non_pawn_material(self.player) {
+            let (null_alpha, null_beta) = if white {(beta - 1, beta)} else {(alpha, alpha + 1)};
+            let null_status = self.do_null_move();
+            let score = self.recursive_best(null_alpha, null_beta, false);
+            self.undo_null_move(null_status);
+            let score = score?;
+            if white && score >= beta {
+                return Some(beta);
+            } else if !white && score <= alpha {
+                return Some(alpha);
+            }
+        }
+
+        // Futility pruning: at the frontier, quiet moves can't bring a hopeless static score back above the window
+        let futility_score = if depth == 1 && !in_check {
+            Some(self.score_function() + FUTILITY_MARGIN * self.player as Score)
+        } else {
+            None
+        };
+
+        // Singular extensions: extend the move from the transposition table if it is much better than the
+        // alternatives. Only tried when the table entry is deep enough and at least as good as its score.
+        let singular_move = match entry {
+            Some(TransEntry { score, depth: entry_depth, bound, best_move: Some(mv) })
+                if excluded_move.is_none()
+                && depth >= SINGULAR_MIN_DEPTH
+                && entry_depth + 3 >= depth
+                && score.abs() <= SCORE_MATE_BOUND
+                && (bound == Bound::Exact || bound == if white {Bound::Lower} else {Bound::Upper}) => {
+                self.is_singular(mv, score, depth)?.then_some(mv)
+            },
+            _ => None
+        };
+
+        // Try the best move from the transposition table first, then captures of valuable pieces,
+        // then quiet moves that have caused cutoffs before
+        let tt_move = entry.and_then(|entry| entry.best_move);
+        let sorted_moves = valid_moves.into_iter().sorted_unstable_by_key(|&mv| {
+            (Some(mv) != tt_move, -self.board[mv.1].value_unsigned(), -self.history[self.history_index(mv)])
+        });
+        for (i, mv) in sorted_moves.enumerate() {
             if self.board[mv.1].is(KING) {
-                println!("King capture!");
-                println!("Engine: {:?}", self);
-                println!("Move: {:?} -> {:?}", mv.0, mv.1);
+                error!("King capture!");
+                error!("Engine: {:?}", self);
+                error!("Move: {:?} -> {:?}", mv.0, mv.1);
                 return None;
             }
+            let quiet = self.board[mv.1].is_empty() && !self.is_promotion(mv);
+            // Checks still count as quiet for the history
+            let history_move = quiet;
+            let recapture = self.last_capture == Some(mv.1);
             let move_status = self.do_move(mv);
-            let score = self.recursive_best(alpha, beta);
+            let gives_check = self.in_check();
+            let quiet = quiet && !gives_check;
+
+            if let Some(futility_score) = futility_score.filter(|_| quiet) {
+                if (white && futility_score <= alpha) || (!white && futility_score >= beta) {
+                    self.undo_move(mv, move_status);
+                    best = if white {best.max(futility_score)} else {best.min(futility_score)};
+                    continue;
+                }
+            }
+
+            // Extensions: checks, recaptures and singular moves are searched one ply deeper,
+            // unless the line has already been extended to twice the depth of the iteration
+            let extension = if self.ply < 2 * self.root_depth && (gives_check || recapture || Some(mv) == singular_move) {1} else {0};
+            self.depth += extension;
+
+            // Principal variation search: only the first move gets a full window, the rest are
+            // expected to fail low and are proven so with a null window. Late quiet moves are
+            // additionally searched one ply shallower (late move reductions).
+            let score = if i == 0 {
+                self.recursive_best(alpha, beta, true)
+            } else {
+                let (null_alpha, null_beta) = if white {(alpha, alpha + 1)} else {(beta - 1, beta)};
+                let reduction = if quiet && !in_check && i >= LMR_MIN_MOVES && depth >= LMR_MIN_DEPTH {1} else {0};
+                self.depth -= reduction;
+                let mut score = self.recursive_best(null_alpha, null_beta, true);
+                self.depth += reduction;
+                // Reduced move fails high, verify at full depth
+                if reduction > 0 && matches!(score, Some(score) if (white && score > alpha) || (!white && score < beta)) {
+                    score = self.recursive_best(null_alpha, null_beta, true);
+                }
+                // Move is better than expected, get its exact score
+                if matches!(score, Some(score) if alpha < score && score < beta) {
+                    score = self.recursive_best(alpha, beta, true);
+                }
+                score
+            };
+            self.depth -= extension;
+            self.undo_move(mv, move_status);
             if score.is_none() {
-                println!("Previous error occured after move: {:?} -> {:?}", mv.0, mv.1);
+                if !self.stop.is_stopped() {
+                    error!("Previous error occured after move: {:?} -> {:?}", mv.0, mv.1);
+                }
                 return None;
             }
             let score = score.unwrap();
-            self.undo_move(mv, move_status);
-            if self.is_white() {
-                best = max_score(best, score);
+            if (white && score > best) || (!white && score < best) {
+                best_move = Some(mv);
+                self.update_pv(mv);
+            }
+            let cutoff = if white {score >= beta} else {score <= alpha};
+            if cutoff && history_move {
+                let index = self.history_index(mv);
+                self.history[index] += (depth * depth) as Score;
+            }
+            if white {
+                best = best.max(score);
                 if best >= beta {
                     break;
                 }
-                alpha = max_score(best, alpha);
+                alpha = alpha.max(best);
             } else {
-                best = min_score(best, score);
+                best = best.min(score);
                 if best <= alpha {
                     break;
                 }
-                beta = min_score(best, beta);
+                beta = beta.min(best);
             }
         }
+
+        if excluded_move.is_some() {
+            return Some(best);
+        }
+        let bound = if best <= alpha_orig {
+            Bound::Upper
+        } else if best >= beta_orig {
+            Bound::Lower
+        } else {
+            Bound::Exact
+        };
+        self.trans_table.store(self.hash, self.ply, TransEntry { score: best, depth, bound, best_move });
         Some(best)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn search(fen: &str, depth: usize) -> (Option<Move>, Score) {
+        let mut engine = First::new();
+        engine.set_seed(Some(1));
+        engine.set_position(fen).unwrap();
+        engine.make_move(&SearchLimits { depth, ..SearchLimits::default() })
+    }
+
+    // Quiet sacrifices and forks that futility pruning and late move reductions must not hide
+    #[test]
+    fn finds_tactics_at_fixed_depth() {
+        for depth in 4..=6 {
+            let (mv, score) = search("kbK5/pp6/1P6/8/8/8/8/R7 w - - 0 1", depth);
+            assert_eq!((mv, mate_in(score)), (Some(((0, 0), (0, 5))), Some(2)), "Ra6 at depth {depth}");
+            let (mv, score) = search("q3k3/8/8/1N6/8/8/4P3/4K3 w - - 0 1", depth);
+            assert_eq!(mv, Some(((1, 4), (2, 6))), "Nc7+ at depth {depth}");
+            assert!(score > 200, "Nc7+ at depth {depth} scored {score}");
+        }
+    }
+
+    // Pawn endings are won or lost on zugzwang, where passing would be the best move. Kd6 takes the
+    // opposition in front of the pawn and wins it, which null move pruning without pieces hides.
+    // In the mutual zugzwang whoever is to move has to let their pawn go.
+    #[test]
+    fn null_move_respects_zugzwang() {
+        for depth in [8, 10] {
+            for fen in ["4k3/8/8/2K1P3/8/8/8/8 w - - 0 1", "4k3/8/2K5/4P3/8/8/8/8 w - - 0 1"] {
+                let (mv, score) = search(fen, depth);
+                assert_eq!(mv.map(|(_, to)| to), Some((3, 5)), "Kd6 in {fen} at depth {depth}");
+                assert!(score > 900, "{fen} at depth {depth} scored {score}");
+            }
+            let (_, score) = search("8/8/8/2Kp4/3Pk3/8/8/8 w - - 0 1", depth);
+            assert!(score < -50, "white to move at depth {depth} scored {score}");
+            let (_, score) = search("8/8/8/2Kp4/3Pk3/8/8/8 b - - 0 1", depth);
+            assert!(score > 50, "black to move at depth {depth} scored {score}");
+        }
+    }
+
+    fn search_lines(fen: &str, depth: usize, multi_pv: usize) -> Vec<PvLine> {
+        let mut engine = First::new();
+        engine.set_seed(Some(1));
+        engine.set_position(fen).unwrap();
+        engine.make_move(&SearchLimits { depth, multi_pv, ..SearchLimits::default() });
+        engine.lines().to_vec()
+    }
+
+    // Extra lines must not change the best one, only add the runners-up in order. The positions have a
+    // clear best move, in quiet ones pruning that depends on the window can rank close moves differently.
+    #[test]
+    fn multi_pv_lines() {
+        for fen in ["q3k3/8/8/1N6/8/8/4P3/4K3 w - - 0 1", "4k3/4p3/8/8/1n6/8/8/Q3K3 b - - 0 1", "rnb1kbnr/pppp1ppp/8/4p1q1/3P4/8/PPP1PPPP/RNBQKBNR w KQkq - 0 3", "rnbqkbnr/ppp2ppp/8/3pp3/4P1Q1/8/PPPP1PPP/RNB1KBNR b KQkq - 0 3"] {
+            let player = if fen.contains(" w ") {1} else {-1};
+            for depth in [3, 4] {
+                let lines = search_lines(fen, depth, 3);
+                assert_eq!(lines.len(), 3, "{fen} at depth {depth}");
+                assert!(lines.iter().map(|line| line.moves[0]).all_unique(), "{fen} at depth {depth}: {lines:?}");
+                assert!(lines.windows(2).all(|pair| pair[0].score * player >= pair[1].score * player), "{fen} at depth {depth}: {lines:?}");
+                let single = search_lines(fen, depth, 1);
+                assert_eq!((single[0].moves[0], single[0].score), (lines[0].moves[0], lines[0].score), "{fen} at depth {depth}");
+            }
+        }
+    }
+}
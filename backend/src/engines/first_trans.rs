@@ -1,12 +1,15 @@
 use crate::types::*;
 use crate::utils::*;
-use super::MoveStatus;
+use crate::trans_table::shift_mate;
+use super::{MoveStatus, NODE_CHECK_INTERVAL, is_en_passant};
 
 use itertools::Itertools;
 use crate::break_block;
 use crate::score_functions::*;
-use std::io::Write;
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+use log::debug;
 
 #[derive(Clone, Debug)]
 pub struct FirstTrans {
@@ -15,68 +18,87 @@ pub struct FirstTrans {
     can_castle: CastleStatus,
     en_passant: Option<Position>,
     depth: usize,
-    pub num_leafs: usize,
-    pub num_skips: usize,
-    transpositions: HashMap<Board, Score>
+    ply: usize,
+    stats: SearchStats,
+    seed: Option<u64>,
+    evaluator: Arc<dyn Evaluator>,
+    transpositions: HashMap<Board, Score>,
+    stop: SearchStop,
+    unreported_nodes: usize
 }
 
 impl Engine for FirstTrans {
-    fn new(fen: &str, depth: usize) -> Result<Self, BadRequest> {
-        let fen_data = parse_fen(fen)?;
-    
-        Ok(FirstTrans {
+    fn new() -> Self {
+        let fen_data = parse_fen(START_FEN).unwrap();
+        FirstTrans {
             board: fen_data.board,
             player: fen_data.player,
             can_castle: fen_data.can_castle,
             en_passant: fen_data.en_passant,
-            depth,
-            num_leafs: 0,
-            num_skips: 0,
-            transpositions: HashMap::new()
-        })
+            depth: DEFAULT_DEPTH,
+            ply: 0,
+            stats: SearchStats::default(),
+            seed: None,
+            evaluator: default_evaluator(),
+            transpositions: HashMap::new(),
+            stop: SearchStop::default(),
+            unreported_nodes: 0
+        }
     }
 
-    fn get_best_moves(&mut self) -> (Vec<Move>, Score) {
+    fn set_position(&mut self, fen: &str) -> Result<(), BadRequest> {
+        let fen_data = parse_fen(fen)?;
+        self.board = fen_data.board;
+        self.player = fen_data.player;
+        self.can_castle = fen_data.can_castle;
+        self.en_passant = fen_data.en_passant;
+        Ok(())
+    }
 
-        let mut best_moves = Vec::new();
-        let mut best = Score::NEG_INFINITY * self.player as Score;
+    // Searches one ply deeper at a time until the depth limit is reached, or the search is stopped
+    // by the caller, the time or the node limit. The deepest completed iteration gives the result.
+    fn get_best_moves(&mut self, limits: &SearchLimits) -> (Vec<Move>, Score) {
+        let start = Instant::now();
+        self.stats = SearchStats::default();
+        self.stop = limits.stop.with_limits(limits.nodes, limits.movetime);
+        self.unreported_nodes = 0;
         let valid_moves = self.board.get_valid(self.player, &self.can_castle, self.en_passant);
-        println!("Number of initial moves: {}", valid_moves.len());
-        println!("Progress: [{}]", (0..valid_moves.len()).map(|_| "-").collect::<String>());
-        print!("           ");
-        for mv in valid_moves.into_iter().sorted_unstable_by_key(|&(_, to)| -self.board[to].value_unsigned() as isize) {
-            print!("#");
-            std::io::stdout().flush().unwrap();
-            let move_status = self.do_move(mv);
-            let score = match self.transpositions.get(&self.board) {
-                Some(score) => {
-                    self.num_skips += 1;
-                    *score
-                },
-                None => {
-                    let score = self.recursive_best(Score::NEG_INFINITY, Score::INFINITY);
-                    self.transpositions.insert(self.board.clone(), score);
-                    score
-                }
-            };
-            self.undo_move(mv, move_status);
-            if self.is_white() && score >= best {
-                if score > best {
-                    best_moves = Vec::new();
-                    best = score
-                }
-                best_moves.push(mv);
-            } else if !self.is_white() && score <= best {
-                if score < best {
-                    best_moves = Vec::new();
-                    best = score
-                }
-                best_moves.push(mv);
+        debug!("Number of initial moves: {}", valid_moves.len());
+        if valid_moves.is_empty() {
+            let king_pos = self.board.get_king_pos(self.player);
+            let score = if self.board.is_checked(king_pos, self.player) {-SCORE_MATE * self.player as Score} else {0};
+            return (Vec::new(), score);
+        }
+        let root_moves = valid_moves.into_iter().sorted_unstable_by_key(|&(_, to)| -self.board[to].value_unsigned() as isize).collect_vec();
+        let mut result = (Vec::new(), -SCORE_INF * self.player as Score);
+        for depth in 1..=limits.depth.max(1) {
+            self.depth = depth;
+            // The cached scores are only valid for the depth they were searched to
+            self.transpositions.clear();
+            match self.search_root(&root_moves) {
+                Some(iteration) => result = iteration,
+                None => break
             }
+            self.stats.depth = depth;
         }
-        println!();
-        println!("Num skips: {}", self.num_skips);
-        (best_moves, best)
+        self.stats.set_time(start.elapsed());
+        result
+    }
+
+    fn stats(&self) -> SearchStats {
+        self.stats.clone()
+    }
+
+    fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    fn set_seed(&mut self, seed: Option<u64>) {
+        self.seed = seed;
+    }
+
+    fn set_evaluator(&mut self, evaluator: Arc<dyn Evaluator>) {
+        self.evaluator = evaluator;
     }
 }
 
@@ -96,6 +118,11 @@ impl FirstTrans {
         status.piece = piece;
         let player = self.player;
 
+        // En passant, the captured pawn is beside the pawn that takes it
+        if is_en_passant(piece, (from, to), status.capture) {
+            self.board[(to.0, from.1)] = 0;
+        }
+
         // Reset en passant square and store previous value in status
         if let Some(en_passant) = self.en_passant {
             status.en_passant = Some(en_passant);
@@ -146,7 +173,21 @@ impl FirstTrans {
             _ => {}
         }
 
+        // Capturing a rook in its corner ends castling on that side
+        if status.capture.is(ROOK) && to.1 == (-player).back_rank() {
+            let rights = &self.can_castle[-player];
+            if (to.0 == 7 && rights.k) || (to.0 == 0 && rights.q) {
+                status.castle_status.get_or_insert_with(|| self.can_castle.clone());
+                if to.0 == 7 {
+                    self.can_castle[-player].k = false;
+                } else {
+                    self.can_castle[-player].q = false;
+                }
+            }
+        }
+
         self.depth -= 1;
+        self.ply += 1;
         self.player *= -1;
         status
 
@@ -162,6 +203,10 @@ impl FirstTrans {
 
         self.player *= -1;
         self.depth += 1;
+        self.ply -= 1;
+        if is_en_passant(status.piece, (from, to), status.capture) {
+            self.board[(to.0, from.1)] = -PAWN * self.player;
+        }
 
         // Handle castle
         if status.piece.is(KING) && from.0.abs_diff(to.0) == 2 {
@@ -179,53 +224,115 @@ impl FirstTrans {
         
     }
 
+    // All moves that share the best score at the current depth, None if the search was stopped
+    fn search_root(&mut self, root_moves: &[Move]) -> Option<(Vec<Move>, Score)> {
+        let mut best_moves = Vec::new();
+        let mut best = -SCORE_INF * self.player as Score;
+        for &mv in root_moves {
+            let move_status = self.do_move(mv);
+            // Mate scores are cached relative to the position, which can be reached at different plies
+            let score = match self.transpositions.get(&self.board).map(|&score| shift_mate(score, -(self.ply as Score))) {
+                Some(score) => {
+                    self.stats.tt_hits += 1;
+                    Some(score)
+                },
+                None => {
+                    let score = self.recursive_best(-SCORE_INF, SCORE_INF);
+                    if let Some(score) = score {
+                        self.transpositions.insert(self.board.clone(), shift_mate(score, self.ply as Score));
+                    }
+                    score
+                }
+            };
+            self.undo_move(mv, move_status);
+            let score = score?;
+            if self.is_white() && score >= best {
+                if score > best {
+                    best_moves = Vec::new();
+                    best = score
+                }
+                best_moves.push(mv);
+            } else if !self.is_white() && score <= best {
+                if score < best {
+                    best_moves = Vec::new();
+                    best = score
+                }
+                best_moves.push(mv);
+            }
+        }
+        Some((best_moves, best))
+    }
+
+    // Node counts are reported to the stop signal in batches, which raises it once a limit is reached
+    fn count_node(&mut self) {
+        self.stats.nodes += 1;
+        self.unreported_nodes += 1;
+        if self.unreported_nodes == NODE_CHECK_INTERVAL {
+            self.stop.add_nodes(NODE_CHECK_INTERVAL);
+            self.unreported_nodes = 0;
+        }
+    }
+
     fn score_function(&self) -> Score {
-        score1(&self.board, -self.player)
+        self.evaluator.evaluate(&self.board, self.player)
     }
 
-    fn recursive_best(&mut self, mut alpha: Score, mut beta: Score) -> Score {
+    fn recursive_best(&mut self, mut alpha: Score, mut beta: Score) -> Option<Score> {
+        self.count_node();
         if self.depth == 0 {
-            self.num_leafs += 1;
-            return self.score_function();
+            self.stats.leaf_nodes += 1;
+            return Some(self.score_function());
+        }
+        // As in First, leaves are always evaluated so that the first iteration completes
+        if self.stop.is_stopped() {
+            return None;
         }
         let valid_moves = self.board.get_valid(self.player, &self.can_castle, self.en_passant);
-        let mut best = -100.0 * self.player as Score;
+        let mut best = -SCORE_INF * self.player as Score;
         let king_pos = self.board.get_king_pos(self.player);
         if valid_moves.is_empty() {
             if self.board.is_checked(king_pos, self.player) {
-                return best - self.depth as Score * self.player as Score;
+                return Some(-(SCORE_MATE - self.ply as Score) * self.player as Score);
             }
-            return 0.0;
-        }  
+            return Some(0);
+        }
         for mv in valid_moves.into_iter().sorted_unstable_by_key(|&(_, to)| -self.board[to].value_unsigned() as isize) {
             let move_status = self.do_move(mv);
-            let score = match self.transpositions.get(&self.board) {
+            // Mate scores are cached relative to the position, which can be reached at different plies
+            let cached = self.transpositions.get(&self.board).map(|&score| shift_mate(score, -(self.ply as Score)));
+            let score = match cached {
                 Some(score) => {
-                    self.num_skips += 1;
-                    *score
+                    self.stats.tt_hits += 1;
+                    Some(score)
                 },
                 None => {
                     let score = self.recursive_best(alpha, beta);
-                    self.transpositions.insert(self.board.clone(), score);
+                    if let Some(score) = score {
+                        self.transpositions.insert(self.board.clone(), shift_mate(score, self.ply as Score));
+                    }
                     score
                 }
             };
             self.undo_move(mv, move_status);
-            if self.is_white() {
-                best = max_score(best, score);
-                if best >= beta {
-                    break;
-                }
-                alpha = max_score(best, alpha);
+            let score = score?;
+            let cutoff = if self.is_white() {
+                best = best.max(score);
+                alpha = alpha.max(best);
+                best >= beta
             } else {
-                best = min_score(best, score);
-                if best <= alpha {
-                    break;
+                best = best.min(score);
+                beta = beta.min(best);
+                best <= alpha
+            };
+            if cutoff {
+                // Only a cached score that ends the search of this node saved any work
+                if cached.is_some() {
+                    self.stats.tt_cutoffs += 1;
                 }
-                beta = min_score(best, beta);
+                break;
             }
         }
-        best
+        Some(best)
     }
 }
 
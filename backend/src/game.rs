@@ -6,6 +6,15 @@ use itertools::Itertools;
 const KNIGHT_COMBINATIONS: [(i8, i8); 8] = [(2, 1), (2, -1), (1, 2), (1, -2), (-1, 2), (-1, -2), (-2, 1), (-2, -1)];
 
 impl Board {
+    // Mirrored top to bottom with the colours of the pieces swapped
+    pub fn flipped(&self) -> Board {
+        let mut flipped = Board([[0; 8]; 8]);
+        for (x, y) in all_coords() {
+            flipped[(x, 7 - y)] = -self[(x, y)];
+        }
+        flipped
+    }
+
     pub fn get_king_pos(&self, player: Player) -> Position {
         all_coords().find(|&pos| self[pos] == KING * player).unwrap()
     }
@@ -60,8 +69,8 @@ impl Board {
         false
     }
 
-    // Determine the lowest valued piece that are attacking / defending a certain square
-    pub fn check_pos(&self, pos: Position, mut player: Player, defender: bool) -> Score {
+    // Determine the type of the lowest valued piece attacking / defending a certain square, 0 if there is none
+    pub fn check_pos(&self, pos: Position, mut player: Player, defender: bool) -> Piece {
         let (x, y) = pos;
         if defender {
             player *= -1;
@@ -70,7 +79,7 @@ impl Board {
         // Test pawn
         for pos in [-1, 1].into_iter().filter_map(|k| (x as i8 + k, y as i8 + player).to_valid()) {
             if self[pos] == PAWN * other {
-                return PAWN.value();
+                return PAWN;
             }
         }
         // Test knight
@@ -78,7 +87,7 @@ impl Board {
             .iter()
             .filter_map(|&(k1, k2)| (x as i8 + k1, y as i8 + k2).to_valid())
             .any(|pos| self[pos] == KNIGHT * other) {
-                return KNIGHT.value();
+                return KNIGHT;
         }
 
         let mut queen = false;
@@ -93,7 +102,7 @@ impl Board {
                 for to in iterator {
                     let piece = self[to] * other;
                     if piece == target {
-                        return target.value();
+                        return target;
                     } else if piece != 0 {
                         if piece == QUEEN {
                             queen = true;
@@ -105,14 +114,14 @@ impl Board {
         }
 
         if queen {
-            return QUEEN.value();
+            return QUEEN;
         }
 
         // Test king
         if get_all_king_moves(pos).any(|pos| self[pos] == KING * other) {
-            return KING.value();
+            return KING;
         }
-        0.0
+        0
     }
 
     pub fn get_valid(&mut self, player: Player, castle_status: &CastleStatus, en_passant: Option<Position>) -> Vec<Move> {
@@ -193,15 +202,25 @@ impl Board {
         }
 
         moves.into_iter().filter(|&(from, to)| {
-            if in_check || self[from].is(KING) {
+            // En passant also takes the pawn beside the capturing one off the board, which can open a line to the king
+            let en_passant = self[from].is(PAWN) && from.0 != to.0 && self[to] == 0;
+            if in_check || en_passant || self[from].is(KING) {
                 let capture = self[to];
+                let taken = (to.0, from.1);
+                let taken_piece = self[taken];
                 self[to] = self[from];
                 self[from] = 0;
+                if en_passant {
+                    self[taken] = 0;
+                }
                 let valid = if self[to].is(KING) {
                     !self.is_checked(to, player)
                 } else {
                     !self.is_checked(king_pos, player)
                 };
+                if en_passant {
+                    self[taken] = taken_piece;
+                }
                 self[from] = self[to];
                 self[to] = capture;
                 valid
@@ -253,10 +272,44 @@ impl Board {
         }        
     }
 
+    // Squares attacked by the piece on `pos`. Sliding pieces attack up to and including the first piece in each direction.
+    pub fn attacks_from(&self, pos: Position) -> Vec<Position> {
+        let (x, y) = pos;
+        let piece = self[pos];
+        match piece.abs() {
+            PAWN => [-1, 1].into_iter().filter_map(|k| (x as i8 + k, y as i8 + piece.signum()).to_valid()).collect(),
+            KNIGHT => KNIGHT_COMBINATIONS
+                .iter()
+                .filter_map(|&(k1, k2)| (x as i8 + k1, y as i8 + k2).to_valid())
+                .collect(),
+            BISHOP => self.slide(get_all_bishop_moves(pos)),
+            ROOK => self.slide(get_all_rook_moves(pos)),
+            QUEEN => {
+                let mut attacks = self.slide(get_all_bishop_moves(pos));
+                attacks.extend(self.slide(get_all_rook_moves(pos)));
+                attacks
+            },
+            KING => get_all_king_moves(pos).filter(|&to| to != pos).collect(),
+            _ => Vec::new()
+        }
+    }
+
+    fn slide(&self, rays: [Vec<Position>; 4]) -> Vec<Position> {
+        rays.into_iter().flat_map(|ray| {
+            let reach = ray.iter().position(|&to| self[to] != 0).map_or(ray.len(), |i| i + 1);
+            ray.into_iter().take(reach)
+        }).collect()
+    }
+
     pub fn count_material(&self) -> Score {
         self.0.iter().flat_map(|row| row.iter().map(IsPiece::value)).sum()
     }
 
+    // Whether a player has any pieces left besides pawns and the king
+    pub fn has_non_pawn_material(&self, player: Player) -> bool {
+        all_coords().any(|pos| player.can_control(self[pos]) && !matches!(self[pos].abs(), PAWN | KING))
+    }
+
     fn smart_checked(&self, player: Player, pos: Position, (from, to): Move) -> bool {
         let other = -player;
         let eps = 0.001;
@@ -0,0 +1,327 @@
+use crate::types::*;
+use crate::utils::{parse_fen, to_fen, move_to_string};
+use crate::game::all_coords;
+
+use itertools::Itertools;
+
+/*
+*   The position of a game played through one of the text protocols. Moves are written in
+*   coordinate notation ("e2e4"), with the piece a pawn promotes to appended ("e7e8q").
+*/
+#[derive(Clone, Debug)]
+pub struct GameState {
+    position: FenData,
+    // The positions before each move, so that moves can be taken back
+    history: Vec<FenData>
+}
+
+// How a game ended, the player in a checkmate is the winner
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Checkmate(Player),
+    Stalemate,
+    FiftyMoves,
+    Repetition,
+    InsufficientMaterial
+}
+
+impl Outcome {
+    // The result as written in PGN
+    pub fn result(&self) -> &'static str {
+        match self {
+            Outcome::Checkmate(1) => "1-0",
+            Outcome::Checkmate(_) => "0-1",
+            _ => "1/2-1/2"
+        }
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Outcome::Checkmate(1) => "White mates",
+            Outcome::Checkmate(_) => "Black mates",
+            Outcome::Stalemate => "Stalemate",
+            Outcome::FiftyMoves => "Draw by fifty move rule",
+            Outcome::Repetition => "Draw by repetition",
+            Outcome::InsufficientMaterial => "Draw by insufficient material"
+        }
+    }
+}
+
+impl Default for GameState {
+    fn default() -> GameState {
+        GameState::from_fen(START_FEN).unwrap()
+    }
+}
+
+impl GameState {
+    pub fn from_fen(fen: &str) -> Result<GameState, String> {
+        let position = parse_fen(fen).map_err(|err| err.0.unwrap_or("Invalid FEN").to_string())?;
+        // The engines expect one king each, and the king of the side that just moved can't be in check
+        let board = &position.board;
+        for player in [1, -1] {
+            if all_coords().filter(|&pos| board[pos] == KING * player).count() != 1 {
+                return Err("Each side needs exactly one king".to_string());
+            }
+        }
+        let other = -position.player;
+        if board.is_checked(board.get_king_pos(other), other) {
+            return Err("The side not to move is in check".to_string());
+        }
+        Ok(GameState { position, history: Vec::new() })
+    }
+
+    pub fn position(&self) -> &FenData {
+        &self.position
+    }
+
+    pub fn fen(&self) -> String {
+        to_fen(&self.position)
+    }
+
+    pub fn player(&self) -> Player {
+        self.position.player
+    }
+
+    pub fn legal_moves(&self) -> Vec<Move> {
+        let position = &self.position;
+        position.board.clone().get_valid(position.player, &position.can_castle, position.en_passant)
+    }
+
+    fn is_promotion(&self, (from, to): Move) -> bool {
+        self.position.board[from].is(PAWN) && (to.1 == 0 || to.1 == 7)
+    }
+
+    // Engines always promote to a queen
+    pub fn move_to_string(&self, mv: Move) -> String {
+        let promotion = if self.is_promotion(mv) {"q"} else {""};
+        format!("{}{promotion}", move_to_string(mv))
+    }
+
+    // Parse a move in coordinate notation, which has to be legal in the current position
+    pub fn parse_move(&self, text: &str) -> Result<(Move, Option<Piece>), String> {
+        let mv = self.legal_moves().into_iter()
+            .find(|&mv| text.starts_with(&move_to_string(mv)))
+            .ok_or_else(|| format!("Illegal move {text}"))?;
+        let promotion = match text[4..].chars().next() {
+            Some(ch) if self.is_promotion(mv) => match ch.to_ascii_lowercase() {
+                'n' => Some(KNIGHT),
+                'b' => Some(BISHOP),
+                'r' => Some(ROOK),
+                'q' => Some(QUEEN),
+                _ => return Err(format!("Invalid promotion in {text}"))
+            },
+            _ => None
+        };
+        Ok((mv, promotion))
+    }
+
+    // The moves of a line, played out to tell which of them are promotions
+    pub fn line_to_string(&self, moves: &[Move]) -> String {
+        let mut game = self.clone();
+        moves.iter().map(|&mv| {
+            let text = game.move_to_string(mv);
+            game.make_move(mv, None);
+            text
+        }).join(" ")
+    }
+
+    // Play a legal move, promoting to `promotion` or a queen
+    pub fn make_move(&mut self, (from, to): Move, promotion: Option<Piece>) {
+        self.history.push(self.position.clone());
+        let position = &mut self.position;
+        let player = position.player;
+        let piece = position.board[from];
+        let capture = position.board[to];
+        position.board[from] = 0;
+        position.board[to] = piece;
+
+        match piece.abs() {
+            PAWN => {
+                // En passant, the captured pawn is beside the pawn that takes it
+                if position.en_passant == Some(to) && from.0 != to.0 {
+                    position.board[(to.0, from.1)] = 0;
+                }
+                if to.1 == 0 || to.1 == 7 {
+                    position.board[to] = promotion.unwrap_or(QUEEN) * player;
+                }
+            },
+            KING => {
+                position.can_castle[player].disable();
+                if from.0 + 2 == to.0 {
+                    position.board[(7, from.1)] = 0;
+                    position.board[(5, from.1)] = ROOK * player;
+                } else if from.0 == to.0 + 2 {
+                    position.board[(0, from.1)] = 0;
+                    position.board[(3, from.1)] = ROOK * player;
+                }
+            },
+            _ => {}
+        }
+        // Moving a rook or capturing one in its corner ends castling on that side
+        for owner in [1, -1] {
+            let rank = owner.back_rank();
+            if from == (7, rank) || to == (7, rank) {
+                position.can_castle[owner].k = false;
+            }
+            if from == (0, rank) || to == (0, rank) {
+                position.can_castle[owner].q = false;
+            }
+        }
+
+        position.en_passant = (piece.is(PAWN) && from.1.abs_diff(to.1) == 2).then(|| (from.0, player.pawn_move(from.1)));
+        position.half_moves = if piece.is(PAWN) || capture != 0 {0} else {position.half_moves + 1};
+        if !player.is_white() {
+            position.move_number += 1;
+        }
+        position.player = -player;
+    }
+
+    // Take back the last move, returns false when there is none
+    pub fn undo(&mut self) -> bool {
+        match self.history.pop() {
+            Some(position) => {
+                self.position = position;
+                true
+            },
+            None => false
+        }
+    }
+
+    pub fn outcome(&self) -> Option<Outcome> {
+        let position = &self.position;
+        let board = &position.board;
+        let player = position.player;
+        if self.legal_moves().is_empty() {
+            return Some(match board.is_checked(board.get_king_pos(player), player) {
+                true => Outcome::Checkmate(-player),
+                false => Outcome::Stalemate
+            });
+        }
+        if position.half_moves >= 100 {
+            return Some(Outcome::FiftyMoves);
+        }
+        // Captures and pawn moves can't be undone, so only the positions since the last one can repeat
+        let repetitions = self.history.iter()
+            .rev()
+            .take(position.half_moves)
+            .filter(|earlier| earlier.board == *board && earlier.player == player && earlier.can_castle == position.can_castle && earlier.en_passant == position.en_passant)
+            .count();
+        if repetitions >= 2 {
+            return Some(Outcome::Repetition);
+        }
+        // Neither side can mate with only a single knight or bishop left
+        let pieces: Vec<Piece> = all_coords().map(|pos| board[pos].abs()).filter(|&piece| piece != 0 && piece != KING).collect();
+        if pieces.len() <= 1 && pieces.iter().all(|&piece| piece == KNIGHT || piece == BISHOP) {
+            return Some(Outcome::InsufficientMaterial);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn play(game: &mut GameState, moves: &str) {
+        for text in moves.split_whitespace() {
+            let (mv, promotion) = game.parse_move(text).unwrap();
+            game.make_move(mv, promotion);
+        }
+    }
+
+    fn game(fen: &str) -> GameState {
+        GameState::from_fen(fen).unwrap()
+    }
+
+    #[test]
+    fn parse_moves() {
+        let game = GameState::default();
+        assert_eq!(game.parse_move("e2e4"), Ok((((4, 1), (4, 3)), None)));
+        assert!(game.parse_move("e2e5").is_err());
+        assert!(game.parse_move("e7e5").is_err());
+        assert!(game.parse_move("e2").is_err());
+    }
+
+    #[test]
+    fn parse_promotions() {
+        let game = game("8/4P1k1/8/8/8/8/8/4K3 w - - 0 1");
+        let mv = ((4, 6), (4, 7));
+        assert_eq!(game.parse_move("e7e8q"), Ok((mv, Some(QUEEN))));
+        assert_eq!(game.parse_move("e7e8n"), Ok((mv, Some(KNIGHT))));
+        assert_eq!(game.parse_move("e7e8R"), Ok((mv, Some(ROOK))));
+        // Left out, the pawn becomes a queen
+        assert_eq!(game.parse_move("e7e8"), Ok((mv, None)));
+        assert!(game.parse_move("e7e8k").is_err());
+        assert_eq!(game.move_to_string(mv), "e7e8q");
+
+        let mut promoted = game.clone();
+        play(&mut promoted, "e7e8n");
+        assert_eq!(promoted.position().board[(4, 7)], KNIGHT);
+    }
+
+    #[test]
+    fn en_passant_removes_the_pawn() {
+        let mut game = game("4k3/8/8/8/3p4/8/4P3/4K3 w - - 0 1");
+        play(&mut game, "e2e4 d4e3");
+        assert_eq!(game.fen(), "4k3/8/8/8/8/4p3/8/4K3 w - - 0 2");
+        assert!(game.undo());
+        assert_eq!(game.position().board[(4, 3)], PAWN);
+    }
+
+    #[test]
+    fn checkmate_and_stalemate() {
+        let mut mated = GameState::default();
+        play(&mut mated, "f2f3 e7e5 g2g4 d8h4");
+        assert_eq!(mated.outcome(), Some(Outcome::Checkmate(-1)));
+        assert_eq!(mated.outcome().unwrap().result(), "0-1");
+
+        let stalemate = game("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1");
+        assert_eq!(stalemate.outcome(), Some(Outcome::Stalemate));
+        assert_eq!(stalemate.outcome().unwrap().result(), "1/2-1/2");
+    }
+
+    #[test]
+    fn insufficient_material() {
+        for fen in ["4k3/8/8/8/8/8/8/4K3 w - - 0 1", "4k3/8/8/8/8/8/8/4KN2 w - - 0 1", "4k3/8/2b5/8/8/8/8/4K3 w - - 0 1"] {
+            assert_eq!(game(fen).outcome(), Some(Outcome::InsufficientMaterial), "{fen}");
+        }
+        for fen in ["4k3/8/8/8/8/8/8/3BKN2 w - - 0 1", "4k3/8/2b5/8/8/8/8/4KN2 w - - 0 1", "4k3/8/8/8/8/8/4P3/4K3 w - - 0 1"] {
+            assert_eq!(game(fen).outcome(), None, "{fen}");
+        }
+    }
+
+    #[test]
+    fn fifty_moves() {
+        assert_eq!(game("4k3/8/8/8/8/8/8/R3K3 w - - 99 80").outcome(), None);
+        assert_eq!(game("4k3/8/8/8/8/8/8/R3K3 w - - 100 80").outcome(), Some(Outcome::FiftyMoves));
+    }
+
+    #[test]
+    fn threefold_repetition() {
+        let mut game = GameState::default();
+        play(&mut game, "g1f3 g8f6 f3g1 f6g8");
+        assert_eq!(game.outcome(), None);
+        play(&mut game, "g1f3 g8f6 f3g1 f6g8");
+        assert_eq!(game.outcome(), Some(Outcome::Repetition));
+    }
+
+    #[test]
+    fn repetition_needs_the_same_castling_rights() {
+        let mut game = game("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1");
+        // The rooks are back where they started, but can't castle any more
+        play(&mut game, "a1b1 a8b8 b1a1 b8a8 a1b1 a8b8 b1a1 b8a8");
+        assert_eq!(game.outcome(), None);
+        play(&mut game, "a1b1 a8b8 b1a1 b8a8");
+        assert_eq!(game.outcome(), Some(Outcome::Repetition));
+    }
+
+    #[test]
+    fn repetition_needs_the_same_en_passant_square() {
+        let mut game = game("4k3/8/8/8/3p4/8/4P3/4K3 w - - 0 1");
+        // Only right after the double step can the pawn be taken en passant
+        play(&mut game, "e2e4 e8d8 e1f1 d8e8 f1e1 e8d8 e1f1 d8e8 f1e1");
+        assert_eq!(game.outcome(), None);
+        play(&mut game, "e8d8 e1f1 d8e8 f1e1");
+        assert_eq!(game.outcome(), Some(Outcome::Repetition));
+    }
+}
@@ -0,0 +1,19 @@
+#[macro_use]
+extern crate lazy_static;
+
+pub mod types;
+pub mod engines;
+pub mod utils;
+pub mod game;
+pub mod score_functions;
+// Experimental board representation that nothing uses yet
+#[allow(dead_code, unused_imports)]
+mod byte_board;
+pub mod zobrist;
+pub mod trans_table;
+pub mod skill;
+pub mod tuner;
+pub mod checks;
+pub mod game_state;
+pub mod uci;
+pub mod xboard;
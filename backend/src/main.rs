@@ -1,57 +1,83 @@
 #[macro_use]
 extern crate rocket;
 
-#[macro_use]
-extern crate lazy_static;
-
-use std::time::Instant;
-use rocket::http::Header;
-use rocket::{Request, Response};
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use rocket::http::{Header, Status};
+use rocket::{Request, Response, State};
+use rocket::response::status;
 use rocket::fairing::{Fairing, Info, Kind};
 use rocket::serde::json::Json;
 use serde::Serialize;
+use log::{info, warn};
 
-mod types;
-mod engines;
-mod utils;
-mod game;
-mod score_functions;
-mod byte_board;
-
-use crate::engines::*;
-use types::*;
-use utils::pos_to_string;
+use rust_chess::engines::*;
+use rust_chess::types::*;
+use rust_chess::utils::{pos_to_string, move_to_string, mate_in, parse_fen};
+use rust_chess::skill::Skill;
+use rust_chess::score_functions::{Evaluator, EvaluatorEntry, EvalParams, EvalTrace, EVALUATORS, DEFAULT_EVALUATOR, find_evaluator};
 
+/*
+*   `score` is in centipawns from white's point of view and is left out when the engine has
+*   found a forced mate. `mate` is then the number of moves until mate, negative if black mates.
+*/
 #[derive(Serialize, Debug)]
 pub struct MoveResponse {
     pub from: String,
     pub to: String,
-    pub score: Score,
-    pub checkmate: bool
+    pub score: Option<Score>,
+    pub mate: Option<i32>,
+    pub checkmate: bool,
+    pub lines: Vec<LineResponse>,
+    pub stats: SearchStats
+}
+
+// One of the best lines found, with the moves in coordinate notation ("e2e4")
+#[derive(Serialize, Debug)]
+pub struct LineResponse {
+    pub moves: Vec<String>,
+    pub score: Option<Score>,
+    pub mate: Option<i32>
 }
 
 impl MoveResponse {
-    fn new(mv: Option<Move>, score: Score) -> MoveResponse {
-        let mut res = match mv {
+    fn new(mv: Option<Move>, score: Score, lines: &[PvLine], stats: SearchStats) -> MoveResponse {
+        let mate = mate_in(score);
+        let score = if mate.is_none() {Some(score)} else {None};
+        let lines = lines.iter().map(LineResponse::new).collect();
+        match mv {
             Some((from, to)) => MoveResponse {
                 from: pos_to_string(from), 
                 to: pos_to_string(to), 
                 score,
-                checkmate: false
+                mate,
+                checkmate: false,
+                lines,
+                stats
             },
             None => MoveResponse {
                 from: String::new(), 
                 to: String::new(), 
                 score,
-                checkmate: true
+                mate,
+                checkmate: true,
+                lines,
+                stats
             }
-        };
-        if score == Score::INFINITY {
-            res.score = 100.0;
-        } else if score == Score::NEG_INFINITY {
-            res.score = -100.0;
         }
-        res
+    }
+}
+
+impl LineResponse {
+    fn new(line: &PvLine) -> LineResponse {
+        let mate = mate_in(line.score);
+        LineResponse {
+            moves: line.moves.iter().copied().map(move_to_string).collect(),
+            score: if mate.is_none() {Some(line.score)} else {None},
+            mate
+        }
     }
 }
 
@@ -73,23 +99,236 @@ impl Fairing for CORS {
         response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
     }
 }
+// Searches that are currently running, by the id the client gave them, so that they can be stopped
+#[derive(Default)]
+pub struct Searches(Mutex<HashMap<String, SearchStop>>);
+
+// Keeps the search registered under its id until the request is done
+struct SearchGuard<'a> {
+    searches: &'a Searches,
+    id: Option<String>
+}
+
+impl<'a> SearchGuard<'a> {
+    // Ids have to be unique among the running searches, or /stop couldn't tell them apart
+    fn new(searches: &'a Searches, id: Option<String>, stop: SearchStop) -> Result<SearchGuard<'a>, status::Conflict<&'static str>> {
+        if let Some(id) = &id {
+            let mut searches = searches.0.lock().unwrap();
+            if searches.contains_key(id) {
+                return Err(status::Conflict(Some("A search with this id is already running")));
+            }
+            searches.insert(id.clone(), stop);
+        }
+        Ok(SearchGuard { searches, id })
+    }
+}
+
+#[derive(Responder, Debug)]
+enum SearchError {
+    BadRequest(BadRequest),
+    Conflict(status::Conflict<&'static str>)
+}
+
+impl From<BadRequest> for SearchError {
+    fn from(err: BadRequest) -> SearchError {
+        SearchError::BadRequest(err)
+    }
+}
+
+impl Drop for SearchGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = &self.id {
+            self.searches.0.lock().unwrap().remove(id);
+        }
+    }
+}
 
+// Idle engines are kept at most this many at a time, each holds on to its transposition table
+const MAX_IDLE_ENGINES: usize = 4;
 
-#[get("/make_move?<fen>")]
-fn make_move(fen: &str) -> Result<Json<MoveResponse>, BadRequest> {
+// What an engine was created with, searches may only reuse an engine created the same way
+#[derive(PartialEq, Debug)]
+struct EngineKey {
+    engine: &'static str,
+    eval: String,
+    params: Option<String>,
+    skill: Option<u8>,
+    elo: Option<u32>
+}
+
+/*
+*   Engines that are done searching, most recently used last. A search takes one created with the
+*   same options, so that it starts out with the tables filled by the searches before it.
+*/
+#[derive(Default)]
+pub struct Engines(Mutex<Vec<(EngineKey, Box<dyn Engine + Send>)>>);
+
+impl Engines {
+    fn take(&self, key: &EngineKey) -> Option<Box<dyn Engine + Send>> {
+        let mut engines = self.0.lock().unwrap();
+        let i = engines.iter().rposition(|(engine_key, _)| engine_key == key)?;
+        Some(engines.remove(i).1)
+    }
+
+    fn give_back(&self, key: EngineKey, engine: Box<dyn Engine + Send>) {
+        let mut engines = self.0.lock().unwrap();
+        engines.push((key, engine));
+        if engines.len() > MAX_IDLE_ENGINES {
+            engines.remove(0);
+        }
+    }
+}
+
+// The evaluator named by `eval`, with the parameters the server was started with overridden by `params`
+fn create_evaluator(eval: Option<&str>, params: Option<&str>, eval_params: &EvalParams) -> Result<Arc<dyn Evaluator>, BadRequest> {
+    let entry = find_evaluator(eval.unwrap_or(DEFAULT_EVALUATOR)).ok_or(status::BadRequest(Some("Unknown evaluator")))?;
+    let params = match params {
+        Some(overrides) => eval_params.with_overrides(overrides).map_err(|err| {
+            warn!("{err}");
+            status::BadRequest(Some("Invalid evaluation parameters"))
+        })?,
+        None => eval_params.clone()
+    };
+    entry.create(&params).map_err(|err| status::BadRequest(Some(err)))
+}
+
+/*
+*   Search for a move in the given position. The search runs until it reaches `depth` (default 6),
+*   or is cut short by `nodes`, `movetime` (in milliseconds) or a call to /stop with the same `id`.
+*   A search is refused with 409 Conflict while another one with the same `id` is running.
+*   The search goes on if the client disconnects, so `movetime` and /stop are the only ways to end it early.
+*   A cut short search answers with the best move of the deepest completed iteration.
+*   `multipv` sets the number of best lines to include in the response (default 1).
+*   With a `seed` the same request always gets the same move, as long as it isn't cut short by time.
+*   `skill` (0-20) or `elo` weakens the play, full strength by default. A weakened engine
+*   searches at least 4 lines to choose its move from, which are all included in the response.
+*   `engine` picks one of the engines listed by /list, which may not support every parameter,
+*   and `eval` the evaluation function it uses, out of the ones listed by /evaluators.
+*   `params` is a JSON object that overrides some of the evaluation parameters the server was
+*   started with for this request only, for example {"bishop_pair":[40,60]}. See /params.
+*   Searches without a seed reuse the tables of earlier searches with the same engine options.
+*/
+#[allow(clippy::too_many_arguments)]
+#[get("/make_move?<fen>&<engine>&<eval>&<params>&<threads>&<depth>&<nodes>&<movetime>&<multipv>&<seed>&<skill>&<elo>&<id>")]
+async fn make_move(
+    fen: &str,
+    engine: Option<&str>,
+    eval: Option<&str>,
+    params: Option<&str>,
+    threads: Option<usize>,
+    depth: Option<usize>,
+    nodes: Option<usize>,
+    movetime: Option<u64>,
+    multipv: Option<usize>,
+    seed: Option<u64>,
+    skill: Option<u8>,
+    elo: Option<u32>,
+    id: Option<String>,
+    searches: &State<Searches>,
+    engines: &State<Engines>,
+    eval_params: &State<EvalParams>
+) -> Result<Json<MoveResponse>, SearchError> {
     let init = Instant::now();
-    let mut engine = First::new(fen, 6)?;
-    let (mv, score) = engine.make_move();
-    let res = MoveResponse::new(mv, score);
-    println!("Visited leafs: {}", engine.num_leafs);
-    //println!("Score = {score}, response = {:?}", res);
-    println!("Elapsed time: {}ms", init.elapsed().as_millis());
+    let entry = find_engine(engine.unwrap_or(DEFAULT_ENGINE)).ok_or(status::BadRequest(Some("Unknown engine")))?;
+    let key = EngineKey {
+        engine: entry.name,
+        eval: eval.unwrap_or(DEFAULT_EVALUATOR).to_string(),
+        params: params.map(str::to_string),
+        skill,
+        elo
+    };
+    // A seeded search starts from empty tables, otherwise its result would depend on the searches before it
+    let reused = if seed.is_none() {engines.take(&key)} else {None};
+    let mut engine = match reused {
+        Some(engine) => engine,
+        None => entry.create(&EngineOptions {
+            seed,
+            skill: skill.map(Skill::new).or(elo.map(Skill::from_elo)),
+            evaluator: create_evaluator(eval, params, eval_params)?
+        })
+    };
+    engine.set_position(fen)?;
+    let limits = SearchLimits {
+        depth: depth.unwrap_or(DEFAULT_DEPTH).max(1),
+        nodes,
+        movetime: movetime.map(Duration::from_millis),
+        threads,
+        multi_pv: multipv.unwrap_or(1),
+        stop: SearchStop::default(),
+        report: None
+    };
+    let _guard = SearchGuard::new(searches, id, limits.stop.clone()).map_err(SearchError::Conflict)?;
+    let (engine, (mv, score)) = rocket::tokio::task::spawn_blocking(move || {
+        let result = engine.make_move(&limits);
+        (engine, result)
+    }).await.expect("Search thread panicked");
+    let stats = engine.stats();
+    info!("Searched {} nodes to depth {} ({} selective) at {} nodes/s", stats.nodes, stats.depth, stats.seldepth, stats.nps);
+    info!("Elapsed time: {}ms", init.elapsed().as_millis());
+    let res = MoveResponse::new(mv, score, engine.lines(), stats);
+    if seed.is_none() {
+        engines.give_back(key, engine);
+    }
     Ok(Json(res))
 }
 
+// The available engines and the parameters they support
+#[get("/list")]
+fn list_engines() -> Json<&'static [EngineEntry]> {
+    Json(ENGINES)
+}
+
+#[get("/evaluators")]
+fn list_evaluators() -> Json<&'static [EvaluatorEntry]> {
+    Json(EVALUATORS)
+}
+
+/*
+*   Static evaluation of a position broken down by term, for each side from its own point of
+*   view. `eval` and `params` choose the evaluation as for /make_move.
+*/
+#[get("/eval?<fen>&<eval>&<params>")]
+fn eval_trace(fen: &str, eval: Option<&str>, params: Option<&str>, eval_params: &State<EvalParams>) -> Result<Json<EvalTrace>, BadRequest> {
+    let evaluator = create_evaluator(eval, params, eval_params)?;
+    let data = parse_fen(fen)?;
+    Ok(Json(evaluator.trace(&data.board, data.player)))
+}
+
+// The evaluation parameters the server was started with
+#[get("/params")]
+fn eval_params(eval_params: &State<EvalParams>) -> Json<&EvalParams> {
+    Json(eval_params.inner())
+}
+
+#[post("/stop?<id>")]
+fn stop(id: &str, searches: &State<Searches>) -> Status {
+    match searches.0.lock().unwrap().get(id) {
+        Some(stop) => {
+            stop.stop();
+            Status::Ok
+        },
+        None => Status::NotFound
+    }
+}
+
+// Evaluation parameters are read from the JSON or TOML file named by EVAL_PARAMS, if it is set
+fn load_eval_params() -> EvalParams {
+    match env::var("EVAL_PARAMS") {
+        Ok(path) => {
+            let params = EvalParams::from_file(&path).unwrap_or_else(|err| panic!("{err}"));
+            info!("Loaded evaluation parameters from {path}");
+            params
+        },
+        Err(_) => EvalParams::default()
+    }
+}
+
 #[launch]
 fn rocket() -> _ {
     rocket::build()
-        .mount("/engine", routes![make_move])
+        .mount("/engine", routes![make_move, list_engines, list_evaluators, eval_trace, eval_params, stop])
+        .manage(Searches::default())
+        .manage(Engines::default())
+        .manage(load_eval_params())
         .attach(CORS)
 }
\ No newline at end of file
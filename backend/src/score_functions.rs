@@ -1,50 +1,282 @@
+pub mod tapered;
+mod pawns;
+mod king_safety;
+mod pieces;
+mod endgame;
+mod nnue;
+
+pub use tapered::{Tapered, EvalParams};
+pub use nnue::Nnue;
+
 use crate::types::*;
 use crate::game::*;
-use crate::utils::cmp_scores;
 
-pub fn score1(board: &Board, player: Player) -> Score {
-    let mut score = board.count_material();
-    let max_undefended = all_coords().filter(|&pos| player.can_control(board[pos])).filter_map(|pos| {
-        let piece = board[pos].value_unsigned();
+use std::fmt::Debug;
+use std::sync::Arc;
+use serde::Serialize;
+
+/*
+*   Static evaluation used by the search engines. Scores are in centipawns from white's point
+*   of view, with `player` to move. Evaluators are shared between search threads.
+*/
+pub trait Evaluator: Debug + Send + Sync {
+    fn evaluate(&self, board: &Board, player: Player) -> Score;
+
+    // Evaluators that can be updated move by move give every search thread a state of its own
+    fn new_accumulator(&self, _board: &Board) -> Option<Box<dyn Accumulator>> {
+        None
+    }
+
+    // Breakdown of the evaluation by term, evaluators without separate terms only give the total
+    fn trace(&self, board: &Board, player: Player) -> EvalTrace {
+        EvalTrace::new(Vec::new(), self.evaluate(board, player))
+    }
+}
+
+// An evaluation term for each side from its own point of view, it adds white - black to the score
+#[derive(Serialize, Debug)]
+pub struct TraceTerm {
+    pub name: &'static str,
+    pub white: Score,
+    pub black: Score
+}
+
+impl TraceTerm {
+    fn new(name: &'static str, [white, black]: [Score; 2]) -> TraceTerm {
+        TraceTerm { name, white, black }
+    }
+}
+
+/*
+*   `sum` adds up the terms, while `total` is the evaluation itself. Evaluators that break
+*   their score down make the terms add up to it, the others only give the total.
+*/
+#[derive(Serialize, Debug)]
+pub struct EvalTrace {
+    pub terms: Vec<TraceTerm>,
+    pub sum: Score,
+    pub total: Score
+}
+
+impl EvalTrace {
+    fn new(terms: Vec<TraceTerm>, total: Score) -> EvalTrace {
+        let sum = terms.iter().map(|term| term.white - term.black).sum();
+        EvalTrace { terms, sum, total }
+    }
+}
+
+// A square whose content changed by a move
+#[derive(Clone, Copy, Debug)]
+pub struct SquareChange {
+    pub pos: Position,
+    pub old: Piece,
+    pub new: Piece
+}
+
+/*
+*   Incrementally updated evaluation state for one search thread. The engine pushes every move
+*   it makes, with the board after the move, and pops it again when the move is unmade.
+*/
+pub trait Accumulator: Debug + Send {
+    fn boxed_clone(&self) -> Box<dyn Accumulator>;
+
+    // Start over from a new position
+    fn reset(&mut self, board: &Board);
+
+    fn push(&mut self, board: &Board, changes: &[SquareChange]);
+
+    fn pop(&mut self);
+
+    fn evaluate(&self, board: &Board, player: Player) -> Score;
+}
+
+impl Clone for Box<dyn Accumulator> {
+    fn clone(&self) -> Self {
+        self.boxed_clone()
+    }
+}
+
+// Counts material only, with the piece values of the parameters
+#[derive(Debug)]
+pub struct Material {
+    params: EvalParams
+}
+
+impl Material {
+    pub fn new(params: EvalParams) -> Material {
+        Material { params }
+    }
+}
+
+impl Evaluator for Material {
+    fn evaluate(&self, board: &Board, _player: Player) -> Score {
+        net(material(board, &self.params.piece_values))
+    }
+
+    fn trace(&self, board: &Board, player: Player) -> EvalTrace {
+        EvalTrace::new(vec![TraceTerm::new("material", material(board, &self.params.piece_values))], self.evaluate(board, player))
+    }
+}
+
+// Material, piece placement and the most valuable piece that can be taken by the side to move
+#[derive(Debug)]
+pub struct Score1 {
+    params: EvalParams
+}
+
+impl Score1 {
+    pub fn new(params: EvalParams) -> Score1 {
+        Score1 { params }
+    }
+}
+
+impl Evaluator for Score1 {
+    fn evaluate(&self, board: &Board, player: Player) -> Score {
+        score1(board, -player, &self.params)
+    }
+
+    fn trace(&self, board: &Board, player: Player) -> EvalTrace {
+        let params = &self.params;
+        let terms = vec![
+            TraceTerm::new("material", material(board, &params.piece_values)),
+            TraceTerm::new("placement", bad_placement(board, params)),
+            TraceTerm::new("hanging piece", hanging(board, player, &params.piece_values))
+        ];
+        EvalTrace::new(terms, self.evaluate(board, player))
+    }
+}
+
+// Evaluators that have no parameters ignore the ones they are created with
+#[derive(Serialize)]
+pub struct EvaluatorEntry {
+    pub name: &'static str,
+    pub description: &'static str,
+    #[serde(skip)]
+    create: fn(&EvalParams) -> Result<Arc<dyn Evaluator>, &'static str>
+}
+
+impl EvaluatorEntry {
+    pub fn create(&self, params: &EvalParams) -> Result<Arc<dyn Evaluator>, &'static str> {
+        (self.create)(params)
+    }
+}
+
+pub const DEFAULT_EVALUATOR: &str = "tapered";
+
+pub static EVALUATORS: &[EvaluatorEntry] = &[
+    EvaluatorEntry {
+        name: "tapered",
+        description: "Piece-square tables blended between middlegame and endgame, pawn structure, king safety, piece activity, hanging pieces and endgame knowledge",
+        create: |params| Ok(Arc::new(Tapered::new(params.clone())))
+    },
+    EvaluatorEntry {
+        name: "score1",
+        description: "Material, piece placement and hanging pieces",
+        create: |params| Ok(Arc::new(Score1::new(params.clone())))
+    },
+    EvaluatorEntry {
+        name: "material",
+        description: "Material only",
+        create: |params| Ok(Arc::new(Material::new(params.clone())))
+    },
+    EvaluatorEntry {
+        name: "nnue",
+        description: "HalfKP neural network, with the weights read from the file named by NNUE_FILE (default nnue.bin)",
+        create: |_| match Nnue::new() {
+            Some(nnue) => Ok(Arc::new(nnue)),
+            None => Err("NNUE weights are not available")
+        }
+    }
+];
+
+pub fn find_evaluator(name: &str) -> Option<&'static EvaluatorEntry> {
+    EVALUATORS.iter().find(|entry| entry.name == name)
+}
+
+pub fn default_evaluator() -> Arc<dyn Evaluator> {
+    find_evaluator(DEFAULT_EVALUATOR).unwrap().create(&EvalParams::default()).unwrap()
+}
+
+pub fn score1(board: &Board, player: Player, params: &EvalParams) -> Score {
+    let score = net(material(board, &params.piece_values)) + net(bad_placement(board, params));
+    score - max_undefended(board, player, &params.piece_values) * player as Score
+}
+
+fn side(player: Player) -> usize {
+    if player.is_white() {0} else {1}
+}
+
+// Adds up a term that is given for each side from its own point of view
+fn net([white, black]: [Score; 2]) -> Score {
+    white - black
+}
+
+// Value of a piece by type, from a table indexed by piece type - 1
+fn piece_value(values: &[Score; 6], piece: Piece) -> Score {
+    values[piece.unsigned_abs() as usize - 1]
+}
+
+// Material of each side, not counting the kings
+fn material(board: &Board, values: &[Score; 6]) -> [Score; 2] {
+    let mut material = [0; 2];
+    for pos in all_coords().filter(|&pos| !board[pos].is_empty() && !board[pos].is(KING)) {
+        material[side(board[pos].signum())] += piece_value(values, board[pos]);
+    }
+    material
+}
+
+// The piece that the side to move can win counts against the other side
+fn hanging(board: &Board, player: Player, values: &[Score; 6]) -> [Score; 2] {
+    let mut hanging = [0; 2];
+    hanging[side(-player)] = -max_undefended(board, -player, values);
+    hanging
+}
+
+// Value of the most valuable piece of `player` that the opponent can win
+pub fn max_undefended(board: &Board, player: Player, values: &[Score; 6]) -> Score {
+    all_coords().filter(|&pos| player.can_control(board[pos])).filter_map(|pos| {
+        let piece = piece_value(values, board[pos]);
         let attacker = board.check_pos(pos, player, false);
         let defender = board.check_pos(pos, player, true);
-        if is_unprotected(piece, attacker, defender) {
-            let dif = if defender.abs() > SCORE_ERR {attacker} else {0.0};
+        if is_unprotected(piece, attacker, defender, values) {
+            let dif = if defender != 0 {piece_value(values, attacker)} else {0};
             Some(piece - dif)
         } else {
             None
         }
-    }).max_by(cmp_scores).unwrap_or(0.0);
-    score += bad_placement(board);
-    score - max_undefended * player as Score
+    }).max().unwrap_or(0)
 }
 
-fn is_unprotected(piece: Score, attacker: Score, defender: Score) -> bool {
-    attacker.abs() > SCORE_ERR && (defender.abs() < SCORE_ERR || attacker < piece)
+// A king can only take pieces that aren't defended
+fn is_unprotected(piece: Score, attacker: Piece, defender: Piece, values: &[Score; 6]) -> bool {
+    attacker != 0 && (defender == 0 || (attacker != KING && piece_value(values, attacker) < piece))
 }
 
-fn bad_placement(board: &Board) -> Score {
-    let mut res = 0.0;
+// For each side from its own point of view
+fn bad_placement(board: &Board, params: &EvalParams) -> [Score; 2] {
+    let mut res = rooks_behind_pawns(board, params);
     for pos in all_coords().filter(|&pos| board[pos] != 0) {
         let (x, y) = pos;
         let piece = board[pos];
-        let penalty = piece.signum() as Score * 0.1;
+        let penalty = &mut res[side(piece.signum())];
         match piece.abs() {
             KNIGHT if x == 0 || x == 7 => {
-                res -= penalty;
+                *penalty += params.bad_placement;
             },
             BISHOP | KNIGHT if y == 0 || y == 7 => {
-                res -= penalty;
+                *penalty += params.bad_placement;
             }
-            ROOK => {
-                for k in 0..8 {
-                    if board[k][x].is(PAWN) {
-                        res -= penalty;
-                    }
-                }
-            },
             _ => {}
         }
     }
     res
+}
+
+// Rooks are penalised for every pawn on their file
+fn rooks_behind_pawns(board: &Board, params: &EvalParams) -> [Score; 2] {
+    let mut res = [0; 2];
+    for pos in all_coords().filter(|&pos| board[pos].is(ROOK)) {
+        res[side(board[pos].signum())] += params.rook_pawn_on_file * (0..8).filter(|&y| board[y][pos.0].is(PAWN)).count() as Score;
+    }
+    res
 }
\ No newline at end of file
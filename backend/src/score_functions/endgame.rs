@@ -0,0 +1,117 @@
+use crate::types::*;
+use crate::game::all_coords;
+use super::piece_value;
+
+// Scores are multiplied by a scale out of NORMAL_SCALE
+const NORMAL_SCALE: Score = 64;
+const MOPUP_EDGE: Score = 10;
+const MOPUP_CLOSE: Score = 4;
+const MOPUP_CORNER: Score = 20;
+
+// Material of one side
+#[derive(Default)]
+struct Side {
+    pawns: Vec<Position>,
+    knights: usize,
+    bishops: Vec<Position>,
+    // Knights, bishops, rooks and queens in centipawns
+    value: Score
+}
+
+impl Side {
+    fn new(board: &Board, player: Player, values: &[Score; 6]) -> Side {
+        let mut side = Side::default();
+        for pos in all_coords().filter(|&pos| player.can_control(board[pos])) {
+            match board[pos].abs() {
+                PAWN => side.pawns.push(pos),
+                KING => continue,
+                KNIGHT => side.knights += 1,
+                BISHOP => side.bishops.push(pos),
+                _ => {}
+            }
+            if !board[pos].is(PAWN) {
+                side.value += piece_value(values, board[pos]);
+            }
+        }
+        side
+    }
+
+    fn only_bishop(&self, values: &[Score; 6]) -> Option<Position> {
+        (self.bishops.len() == 1 && self.value == piece_value(values, BISHOP)).then(|| self.bishops[0])
+    }
+}
+
+/*
+*   Endgame knowledge on top of the evaluation `score`. When the defending side only has its king
+*   left, the attacker is rewarded for driving it to the edge (or the right corner with bishop and
+*   knight) and for bringing its own king closer. Endings that are hard or impossible to win
+*   despite the material are scaled towards a draw. The material is counted with `values`.
+*/
+pub fn endgame(board: &Board, score: Score, values: &[Score; 6]) -> Score {
+    let strong = if score >= 0 {1} else {-1};
+    let (attacker, defender) = (Side::new(board, strong, values), Side::new(board, -strong, values));
+    let mut score = score;
+    let rook_up = piece_value(values, ROOK) - piece_value(values, PAWN);
+    if defender.pawns.is_empty() && attacker.value >= defender.value + rook_up {
+        score += strong as Score * mopup(board, strong, &attacker, values);
+    }
+    score * scale(board, strong, &attacker, &defender, values) / NORMAL_SCALE
+}
+
+fn mopup(board: &Board, strong: Player, attacker: &Side, values: &[Score; 6]) -> Score {
+    let losing_king = board.get_king_pos(-strong);
+    let winning_king = board.get_king_pos(strong);
+    let kings_distance = losing_king.0.abs_diff(winning_king.0) + losing_king.1.abs_diff(winning_king.1);
+    let bishop_and_knight = attacker.pawns.is_empty() && attacker.knights == 1 && attacker.bishops.len() == 1
+        && attacker.value == piece_value(values, KNIGHT) + piece_value(values, BISHOP);
+    let edge = if bishop_and_knight {
+        // Mate can only be forced in a corner of the bishop's colour
+        let corners = if square_colour(attacker.bishops[0]) == square_colour((0, 0)) {[(0, 0), (7, 7)]} else {[(7, 0), (0, 7)]};
+        let corner_distance = corners.iter().map(|&corner| distance(losing_king, corner)).min().unwrap();
+        MOPUP_CORNER * (7 - corner_distance as Score)
+    } else {
+        let center_distance = losing_king.0.abs_diff(3).min(losing_king.0.abs_diff(4)) + losing_king.1.abs_diff(3).min(losing_king.1.abs_diff(4));
+        MOPUP_EDGE * center_distance as Score
+    };
+    edge + MOPUP_CLOSE * (14 - kings_distance as Score)
+}
+
+fn scale(board: &Board, strong: Player, attacker: &Side, defender: &Side, values: &[Score; 6]) -> Score {
+    if attacker.pawns.is_empty() {
+        // A single minor piece, or two knights, can't force mate
+        let single_minor = attacker.value <= piece_value(values, KNIGHT).max(piece_value(values, BISHOP));
+        if single_minor || (attacker.knights == 2 && attacker.value == 2 * piece_value(values, KNIGHT) && defender.value == 0) {
+            return 0;
+        }
+        // Up less than a rook, like rook against minor piece
+        if attacker.value - defender.value < piece_value(values, ROOK) - piece_value(values, PAWN) {
+            return NORMAL_SCALE / 4;
+        }
+    }
+    if let Some(bishop) = attacker.only_bishop(values) {
+        // Wrong coloured bishop: rook pawns that promote on a square the bishop can't cover,
+        // with the defending king in front of them
+        let file = attacker.pawns.first().map(|pawn| pawn.0);
+        if defender.value == 0 && matches!(file, Some(0 | 7)) && attacker.pawns.iter().all(|pawn| Some(pawn.0) == file) {
+            let promotion = (file.unwrap(), (-strong).back_rank());
+            if square_colour(promotion) != square_colour(bishop) && distance(board.get_king_pos(-strong), promotion) <= 1 {
+                return NORMAL_SCALE / 32;
+            }
+        }
+        // Opposite coloured bishops
+        if defender.only_bishop(values).is_some_and(|other| square_colour(other) != square_colour(bishop)) {
+            let pawn_difference = attacker.pawns.len().abs_diff(defender.pawns.len());
+            return if pawn_difference <= 1 {NORMAL_SCALE / 4} else {NORMAL_SCALE / 2};
+        }
+    }
+    NORMAL_SCALE
+}
+
+fn square_colour((x, y): Position) -> usize {
+    (x + y) % 2
+}
+
+// Number of king moves between two squares
+fn distance(a: Position, b: Position) -> usize {
+    a.0.abs_diff(b.0).max(a.1.abs_diff(b.1))
+}
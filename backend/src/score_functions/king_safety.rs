@@ -0,0 +1,62 @@
+use crate::types::*;
+use crate::game::all_coords;
+use super::side;
+use super::tapered::{EvalParams, PHASE};
+
+// Opponent material, in phase units, at which king safety counts in full
+const FULL_ATTACK_MATERIAL: Score = 12;
+
+/*
+*   King safety of each side from its own point of view: the pawn shield in front of the king,
+*   enemy pawns storming it, open files next to it and the pieces attacking the squares around it.
+*   Each side's score is scaled by the material the opponent has left to attack with.
+*/
+pub fn king_safety(board: &Board, params: &EvalParams) -> [Score; 2] {
+    let mut score = [0; 2];
+    for player in [1, -1] {
+        let king = board.get_king_pos(player);
+        let forward = player as isize;
+        let (king_x, king_y) = (king.0 as isize, king.1 as isize);
+        // Distance in ranks to the closest pawn of `owner` in front of the king on a file
+        let pawn_ahead = |x: usize, owner: Player| {
+            (0..8).filter(|&y| board[(x, y)] == PAWN * owner)
+                .map(|y| (y as isize - king_y) * forward)
+                .filter(|&distance| distance > 0)
+                .min()
+        };
+        let mut safety = 0;
+        for x in (king_x - 1..=king_x + 1).filter(|x| x.is_valid()).map(|x| x as usize) {
+            let own = pawn_ahead(x, player);
+            let enemy = pawn_ahead(x, -player);
+            match own {
+                Some(1) => safety += params.king_shield[0],
+                Some(2) => safety += params.king_shield[1],
+                Some(_) => {},
+                None if enemy.is_none() => safety += params.king_open_file,
+                None => safety += params.king_semi_open_file
+            }
+            if let Some(distance @ 1..=3) = enemy {
+                safety += params.king_storm[distance as usize - 1];
+            }
+        }
+
+        let mut zone = board.attacks_from(king);
+        zone.push(king);
+        let (mut attackers, mut attack_weight, mut material) = (0, 0, 0);
+        for pos in all_coords().filter(|&pos| (-player).can_control(board[pos])) {
+            let kind = board[pos].unsigned_abs() as usize - 1;
+            material += PHASE[kind];
+            if params.king_attack_weight[kind] == 0 {
+                continue;
+            }
+            let hits = board.attacks_from(pos).into_iter().filter(|to| zone.contains(to)).count() as Score;
+            if hits > 0 {
+                attackers += 1;
+                attack_weight += params.king_attack_weight[kind] * hits;
+            }
+        }
+        safety -= attack_weight * params.king_attackers_scale[attackers.min(7)] / 100;
+        score[side(player)] = safety * material.min(FULL_ATTACK_MATERIAL) / FULL_ATTACK_MATERIAL;
+    }
+    score
+}
@@ -0,0 +1,419 @@
+use crate::types::*;
+use crate::game::all_coords;
+use super::{Evaluator, Accumulator, SquareChange};
+
+use std::sync::Arc;
+use log::{info, error};
+
+/*
+*   Efficiently updatable neural network with HalfKP inputs: for each side, every non-king piece
+*   on every square, relative to the position of that side's own king. The first layer is kept
+*   up to date incrementally (the accumulator) while moves are made and unmade, the small
+*   layers after it are evaluated from scratch.
+*
+*   Network file, all little endian:
+*   magic "RCNNUE01"
+*   feature transformer: HIDDEN i16 biases, INPUTS * HIDDEN i16 weights (input major)
+*   layer 1: L1 i32 biases, L1 * 2 * HIDDEN i8 weights (output major)
+*   layer 2: L2 i32 biases, L2 * L1 i8 weights
+*   output: 1 i32 bias, L2 i8 weights
+*/
+const MAGIC: &[u8; 8] = b"RCNNUE01";
+const INPUTS: usize = 64 * 10 * 64;
+const HIDDEN: usize = 256;
+const L1: usize = 32;
+const L2: usize = 32;
+// Fixed point scales of the hidden layers and the output
+const WEIGHT_SHIFT: u32 = 6;
+const OUTPUT_SCALE: i32 = 16;
+const DEFAULT_FILE: &str = "nnue.bin";
+
+lazy_static! {
+    // Loaded once from the file named by NNUE_FILE, or nnue.bin in the working directory
+    static ref NETWORK: Option<Arc<Network>> = {
+        let path = std::env::var("NNUE_FILE").unwrap_or_else(|_| DEFAULT_FILE.to_string());
+        match Network::load(&path) {
+            Ok(network) => {
+                info!("Loaded NNUE weights from {path} (SIMD: {})", network.simd);
+                Some(Arc::new(network))
+            },
+            Err(err) => {
+                error!("Could not load NNUE weights from {path}: {err}");
+                None
+            }
+        }
+    };
+}
+
+pub struct Network {
+    ft_biases: Vec<i16>,
+    ft_weights: Vec<i16>,
+    l1_biases: Vec<i32>,
+    l1_weights: Vec<i8>,
+    l2_biases: Vec<i32>,
+    l2_weights: Vec<i8>,
+    out_bias: i32,
+    out_weights: Vec<i8>,
+    // Use AVX2 when the CPU has it, unless NNUE_SCALAR is set
+    simd: bool
+}
+
+impl std::fmt::Debug for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Network {{ inputs: {INPUTS}, hidden: {HIDDEN}, simd: {} }}", self.simd)
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8]
+}
+
+impl<'a> Reader<'a> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        if self.bytes.len() < N {
+            return Err("File is too short".to_string());
+        }
+        let (head, tail) = self.bytes.split_at(N);
+        self.bytes = tail;
+        Ok(head.try_into().unwrap())
+    }
+
+    fn i8s(&mut self, n: usize) -> Result<Vec<i8>, String> {
+        (0..n).map(|_| self.take::<1>().map(|b| b[0] as i8)).collect()
+    }
+
+    fn i16s(&mut self, n: usize) -> Result<Vec<i16>, String> {
+        (0..n).map(|_| self.take().map(i16::from_le_bytes)).collect()
+    }
+
+    fn i32s(&mut self, n: usize) -> Result<Vec<i32>, String> {
+        (0..n).map(|_| self.take().map(i32::from_le_bytes)).collect()
+    }
+}
+
+impl Network {
+    pub fn load(path: &str) -> Result<Network, String> {
+        let bytes = std::fs::read(path).map_err(|err| err.to_string())?;
+        let mut reader = Reader { bytes: &bytes };
+        if &reader.take::<8>()? != MAGIC {
+            return Err("Not an NNUE file".to_string());
+        }
+        let network = Network {
+            ft_biases: reader.i16s(HIDDEN)?,
+            ft_weights: reader.i16s(INPUTS * HIDDEN)?,
+            l1_biases: reader.i32s(L1)?,
+            l1_weights: reader.i8s(L1 * 2 * HIDDEN)?,
+            l2_biases: reader.i32s(L2)?,
+            l2_weights: reader.i8s(L2 * L1)?,
+            out_bias: reader.i32s(1)?[0],
+            out_weights: reader.i8s(L2)?,
+            simd: simd_available() && std::env::var_os("NNUE_SCALAR").is_none()
+        };
+        if !reader.bytes.is_empty() {
+            return Err("File is too long".to_string());
+        }
+        Ok(network)
+    }
+
+    fn feature_row(&self, index: usize) -> &[i16] {
+        &self.ft_weights[index * HIDDEN..(index + 1) * HIDDEN]
+    }
+
+    // Accumulator of one side computed from scratch
+    fn refresh(&self, accumulator: &mut [i16; HIDDEN], board: &Board, perspective: Player) {
+        accumulator.copy_from_slice(&self.ft_biases);
+        let king = board.get_king_pos(perspective);
+        for pos in all_coords().filter(|&pos| !board[pos].is_empty() && !board[pos].is(KING)) {
+            add_row(accumulator, self.feature_row(feature(perspective, king, board[pos], pos)), self.simd);
+        }
+    }
+
+    // Score in centipawns from white's point of view, with `player` to move
+    fn forward(&self, accumulators: &[[i16; HIDDEN]; 2], player: Player) -> Score {
+        // The side to move comes first
+        let mut input = [0u8; 2 * HIDDEN];
+        let (us, them) = (side(player), side(-player));
+        for (i, &value) in accumulators[us].iter().chain(accumulators[them].iter()).enumerate() {
+            input[i] = value.clamp(0, 127) as u8;
+        }
+        let mut hidden1 = [0u8; L1];
+        for (i, out) in hidden1.iter_mut().enumerate() {
+            let sum = self.l1_biases[i] + dot(&input, &self.l1_weights[i * 2 * HIDDEN..(i + 1) * 2 * HIDDEN], self.simd);
+            *out = (sum >> WEIGHT_SHIFT).clamp(0, 127) as u8;
+        }
+        let mut hidden2 = [0u8; L2];
+        for (i, out) in hidden2.iter_mut().enumerate() {
+            let sum = self.l2_biases[i] + dot(&hidden1, &self.l2_weights[i * L1..(i + 1) * L1], false);
+            *out = (sum >> WEIGHT_SHIFT).clamp(0, 127) as u8;
+        }
+        let output = (self.out_bias + dot(&hidden2, &self.out_weights, false)) / OUTPUT_SCALE;
+        output.clamp(-SCORE_MATE_BOUND + 1, SCORE_MATE_BOUND - 1) * player as Score
+    }
+}
+
+fn side(player: Player) -> usize {
+    if player.is_white() {0} else {1}
+}
+
+// Input index of `piece` on `pos` as seen from `perspective`, whose king is on `king`.
+// Black sees the board flipped, so that both sides use the same weights.
+fn feature(perspective: Player, king: Position, piece: Piece, pos: Position) -> usize {
+    let orient = |(x, y): Position| if perspective.is_white() {y * 8 + x} else {(7 - y) * 8 + x};
+    let kind = (piece.unsigned_abs() as usize - 1) * 2 + (piece.signum() != perspective) as usize;
+    (orient(king) * 10 + kind) * 64 + orient(pos)
+}
+
+// Evaluates the network from scratch, for engines that don't keep an accumulator
+#[derive(Debug)]
+pub struct Nnue {
+    network: Arc<Network>
+}
+
+impl Nnue {
+    pub fn new() -> Option<Nnue> {
+        NETWORK.clone().map(|network| Nnue { network })
+    }
+}
+
+impl Evaluator for Nnue {
+    fn evaluate(&self, board: &Board, player: Player) -> Score {
+        let mut accumulators = [[0; HIDDEN]; 2];
+        for perspective in [1, -1] {
+            self.network.refresh(&mut accumulators[side(perspective)], board, perspective);
+        }
+        self.network.forward(&accumulators, player)
+    }
+
+    fn new_accumulator(&self, board: &Board) -> Option<Box<dyn Accumulator>> {
+        let mut accumulator = NnueAccumulator { network: self.network.clone(), stack: Vec::new(), top: 0 };
+        accumulator.reset(board);
+        Some(Box::new(accumulator))
+    }
+}
+
+// One entry per ply, so that unmaking a move is just moving back down the stack
+#[derive(Clone, Debug)]
+struct NnueAccumulator {
+    network: Arc<Network>,
+    stack: Vec<[[i16; HIDDEN]; 2]>,
+    top: usize
+}
+
+impl Accumulator for NnueAccumulator {
+    fn boxed_clone(&self) -> Box<dyn Accumulator> {
+        Box::new(self.clone())
+    }
+
+    fn reset(&mut self, board: &Board) {
+        let mut accumulators = [[0; HIDDEN]; 2];
+        for perspective in [1, -1] {
+            self.network.refresh(&mut accumulators[side(perspective)], board, perspective);
+        }
+        self.stack.clear();
+        self.stack.push(accumulators);
+        self.top = 0;
+    }
+
+    fn push(&mut self, board: &Board, changes: &[SquareChange]) {
+        if self.top + 1 == self.stack.len() {
+            self.stack.push(self.stack[self.top]);
+        } else {
+            self.stack[self.top + 1] = self.stack[self.top];
+        }
+        self.top += 1;
+        let network = &self.network;
+        let accumulators = &mut self.stack[self.top];
+        for perspective in [1, -1] {
+            let accumulator = &mut accumulators[side(perspective)];
+            // Every feature depends on the own king, so a king move means starting over
+            if changes.iter().any(|change| change.old == KING * perspective || change.new == KING * perspective) {
+                network.refresh(accumulator, board, perspective);
+                continue;
+            }
+            let king = board.get_king_pos(perspective);
+            for change in changes {
+                if !change.old.is_empty() && !change.old.is(KING) {
+                    sub_row(accumulator, network.feature_row(feature(perspective, king, change.old, change.pos)), network.simd);
+                }
+                if !change.new.is_empty() && !change.new.is(KING) {
+                    add_row(accumulator, network.feature_row(feature(perspective, king, change.new, change.pos)), network.simd);
+                }
+            }
+        }
+    }
+
+    fn pop(&mut self) {
+        self.top -= 1;
+    }
+
+    fn evaluate(&self, _board: &Board, player: Player) -> Score {
+        self.network.forward(&self.stack[self.top], player)
+    }
+}
+
+fn simd_available() -> bool {
+    #[cfg(target_arch = "x86_64")]
+    {
+        is_x86_feature_detected!("avx2")
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        false
+    }
+}
+
+#[cfg_attr(not(target_arch = "x86_64"), allow(unused_variables))]
+fn add_row(accumulator: &mut [i16; HIDDEN], row: &[i16], simd: bool) {
+    #[cfg(target_arch = "x86_64")]
+    if simd {
+        // Safe since `simd` is only set when the CPU supports AVX2
+        unsafe { avx2::add_row(accumulator, row) };
+        return;
+    }
+    for (value, &weight) in accumulator.iter_mut().zip(row) {
+        *value = value.wrapping_add(weight);
+    }
+}
+
+#[cfg_attr(not(target_arch = "x86_64"), allow(unused_variables))]
+fn sub_row(accumulator: &mut [i16; HIDDEN], row: &[i16], simd: bool) {
+    #[cfg(target_arch = "x86_64")]
+    if simd {
+        unsafe { avx2::sub_row(accumulator, row) };
+        return;
+    }
+    for (value, &weight) in accumulator.iter_mut().zip(row) {
+        *value = value.wrapping_sub(weight);
+    }
+}
+
+// Inputs are at most 127, so the AVX2 version can't saturate and gives the same result
+#[cfg_attr(not(target_arch = "x86_64"), allow(unused_variables))]
+fn dot(input: &[u8], weights: &[i8], simd: bool) -> i32 {
+    #[cfg(target_arch = "x86_64")]
+    if simd && input.len().is_multiple_of(32) {
+        return unsafe { avx2::dot(input, weights) };
+    }
+    input.iter().zip(weights).map(|(&a, &b)| a as i32 * b as i32).sum()
+}
+
+#[cfg(target_arch = "x86_64")]
+mod avx2 {
+    use super::HIDDEN;
+    use std::arch::x86_64::*;
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn add_row(accumulator: &mut [i16; HIDDEN], row: &[i16]) {
+        assert_eq!(row.len(), HIDDEN);
+        for i in (0..HIDDEN).step_by(16) {
+            let a = _mm256_loadu_si256(accumulator.as_ptr().add(i) as *const __m256i);
+            let b = _mm256_loadu_si256(row.as_ptr().add(i) as *const __m256i);
+            _mm256_storeu_si256(accumulator.as_mut_ptr().add(i) as *mut __m256i, _mm256_add_epi16(a, b));
+        }
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn sub_row(accumulator: &mut [i16; HIDDEN], row: &[i16]) {
+        assert_eq!(row.len(), HIDDEN);
+        for i in (0..HIDDEN).step_by(16) {
+            let a = _mm256_loadu_si256(accumulator.as_ptr().add(i) as *const __m256i);
+            let b = _mm256_loadu_si256(row.as_ptr().add(i) as *const __m256i);
+            _mm256_storeu_si256(accumulator.as_mut_ptr().add(i) as *mut __m256i, _mm256_sub_epi16(a, b));
+        }
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn dot(input: &[u8], weights: &[i8]) -> i32 {
+        assert_eq!(input.len(), weights.len());
+        let ones = _mm256_set1_epi16(1);
+        let mut sum = _mm256_setzero_si256();
+        for i in (0..input.len()).step_by(32) {
+            let a = _mm256_loadu_si256(input.as_ptr().add(i) as *const __m256i);
+            let b = _mm256_loadu_si256(weights.as_ptr().add(i) as *const __m256i);
+            let products = _mm256_maddubs_epi16(a, b);
+            sum = _mm256_add_epi32(sum, _mm256_madd_epi16(products, ones));
+        }
+        let mut lanes = [0i32; 8];
+        _mm256_storeu_si256(lanes.as_mut_ptr() as *mut __m256i, sum);
+        lanes.iter().sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_state::GameState;
+    use crate::utils::seeded_rng;
+    use rand::Rng;
+
+    const FENS: [&str; 4] = [
+        START_FEN,
+        "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+        "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+        "rnbqkbnr/ppp1p1pp/8/3pPp2/8/8/PPPP1PPP/RNBQKBNR w KQkq f6 0 3"
+    ];
+
+    // Random weights, kept small enough that the accumulators stay inside the clipped range
+    fn random_network(simd: bool) -> Network {
+        let mut rng = seeded_rng(Some(1));
+        Network {
+            ft_biases: (0..HIDDEN).map(|_| rng.gen_range(-64..64)).collect(),
+            ft_weights: (0..INPUTS * HIDDEN).map(|_| rng.gen_range(-32..32)).collect(),
+            l1_biases: (0..L1).map(|_| rng.gen_range(-1000..1000)).collect(),
+            l1_weights: (0..L1 * 2 * HIDDEN).map(|_| rng.gen()).collect(),
+            l2_biases: (0..L2).map(|_| rng.gen_range(-1000..1000)).collect(),
+            l2_weights: (0..L2 * L1).map(|_| rng.gen()).collect(),
+            out_bias: rng.gen_range(-1000..1000),
+            out_weights: (0..L2).map(|_| rng.gen()).collect(),
+            simd
+        }
+    }
+
+    fn changes(before: &Board, after: &Board) -> Vec<SquareChange> {
+        all_coords()
+            .filter(|&pos| before[pos] != after[pos])
+            .map(|pos| SquareChange { pos, old: before[pos], new: after[pos] })
+            .collect()
+    }
+
+    // Accumulators and scores along a line of moves, checked against a refresh at every ply
+    fn play(network: Arc<Network>, fen: &str) -> Vec<([[i16; HIDDEN]; 2], Score)> {
+        let nnue = Nnue { network: network.clone() };
+        let mut game = GameState::from_fen(fen).unwrap();
+        let mut accumulator = NnueAccumulator { network, stack: Vec::new(), top: 0 };
+        accumulator.reset(&game.position().board);
+        let mut line = vec![(accumulator.stack[0], nnue.evaluate(&game.position().board, game.player()))];
+        for ply in 0..16 {
+            let moves = game.legal_moves();
+            if moves.is_empty() {
+                break;
+            }
+            let before = game.position().board.clone();
+            game.make_move(moves[ply * 7 % moves.len()], None);
+            let position = game.position();
+            accumulator.push(&position.board, &changes(&before, &position.board));
+            let score = accumulator.evaluate(&position.board, position.player);
+            assert_eq!(score, nnue.evaluate(&position.board, position.player), "{fen} after {ply} plies");
+            line.push((accumulator.stack[accumulator.top], score));
+        }
+        for (accumulators, score) in line.iter().rev().skip(1) {
+            accumulator.pop();
+            game.undo();
+            assert_eq!(accumulator.stack[accumulator.top], *accumulators);
+            assert_eq!(accumulator.evaluate(&game.position().board, game.player()), *score);
+        }
+        line
+    }
+
+    #[test]
+    fn simd_matches_scalar() {
+        if !simd_available() {
+            return;
+        }
+        let scalar = Arc::new(random_network(false));
+        let simd = Arc::new(random_network(true));
+        for fen in FENS {
+            assert!(play(scalar.clone(), fen) == play(simd.clone(), fen), "{fen}");
+        }
+    }
+}
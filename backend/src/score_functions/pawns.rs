@@ -0,0 +1,136 @@
+use crate::types::*;
+use crate::game::all_coords;
+use super::side;
+use super::tapered::{EvalParams, Weight};
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Set in every stored entry, so that a structure scoring [0, 0] is told apart from an empty slot
+const VALID: u64 = 1 << 63;
+
+/*
+*   Cache of pawn structure scores, keyed on the pawn positions only. Shared between search
+*   threads the same way as the transposition table: each slot stores the key xor:ed with the
+*   data, so that a slot torn by two threads fails the key check.
+*/
+pub struct PawnTable {
+    slots: Vec<(AtomicU64, AtomicU64)>,
+    mask: usize
+}
+
+impl PawnTable {
+    pub fn new(num_slots: usize) -> PawnTable {
+        let num_slots = num_slots.max(1).next_power_of_two();
+        PawnTable {
+            slots: (0..num_slots).map(|_| (AtomicU64::new(0), AtomicU64::new(0))).collect(),
+            mask: num_slots - 1
+        }
+    }
+
+    // Middlegame and endgame score of the pawn structure for each side, computed on a miss
+    pub fn probe(&self, key: u64, compute: impl FnOnce() -> [Weight; 2]) -> [Weight; 2] {
+        let (slot_key, slot_data) = &self.slots[key as usize & self.mask];
+        let data = slot_data.load(Ordering::Relaxed);
+        if slot_key.load(Ordering::Relaxed) ^ data == key && data & VALID != 0 {
+            return unpack(data);
+        }
+        let weight = compute();
+        let data = pack(weight);
+        slot_key.store(key ^ data, Ordering::Relaxed);
+        slot_data.store(data, Ordering::Relaxed);
+        weight
+    }
+}
+
+// 15 bits for each of the four scores, far more than a pawn structure can be worth
+fn pack(weights: [Weight; 2]) -> u64 {
+    weights.as_flattened().iter().enumerate()
+        .fold(VALID, |data, (i, &score)| data | (score as u16 as u64 & 0x7fff) << (15 * i))
+}
+
+fn unpack(data: u64) -> [Weight; 2] {
+    // Shifting back down from the top bit restores the sign
+    let score = |i: usize| ((data >> (15 * i)) as u16 as i16) << 1 >> 1;
+    [[score(0), score(1)], [score(2), score(3)]].map(|weight| weight.map(Score::from))
+}
+
+impl std::fmt::Debug for PawnTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PawnTable {{ slots: {} }}", self.slots.len())
+    }
+}
+
+// Doubled, isolated, backward, passed and connected pawns and pawn islands, for each side from its own point of view
+pub fn pawn_structure(board: &Board, params: &EvalParams) -> [Weight; 2] {
+    let mut score = [[0; 2]; 2];
+    for player in [1, -1] {
+        let is_pawn = |x: isize, y: isize, owner: Player| x.is_valid() && y.is_valid() && board[(x as usize, y as usize)] == PAWN * owner;
+        let mut add = |weight: Weight, times: Score| {
+            score[side(player)][0] += weight[0] * times;
+            score[side(player)][1] += weight[1] * times;
+        };
+        let mut files = [0; 8];
+        all_coords().filter(|&pos| board[pos] == PAWN * player).for_each(|(x, _)| files[x] += 1);
+        for count in files {
+            add(params.doubled, (count - 1).max(0));
+        }
+        let islands = (0..8).filter(|&x| files[x] > 0 && (x == 0 || files[x - 1] == 0)).count() as Score;
+        add(params.pawn_island, (islands - 1).max(0));
+
+        let forward = player as isize;
+        for (x, y) in all_coords().filter(|&pos| board[pos] == PAWN * player) {
+            let (x, y) = (x as isize, y as isize);
+            let ahead = |y2: isize| (y2 - y) * forward > 0;
+            let file_pawns = |x2: isize, owner: Player| (0..8).filter(move |&y2| is_pawn(x2, y2, owner));
+            let isolated = files.get((x - 1) as usize).is_none_or(|&n| n == 0) && files.get((x + 1) as usize).is_none_or(|&n| n == 0);
+            let passed = [x - 1, x, x + 1].iter().all(|&x2| file_pawns(x2, -player).all(|y2| !ahead(y2)))
+                && file_pawns(x, player).all(|y2| !ahead(y2));
+            let supported = is_pawn(x - 1, y - forward, player) || is_pawn(x + 1, y - forward, player);
+            let phalanx = is_pawn(x - 1, y, player) || is_pawn(x + 1, y, player);
+            // No friendly pawn beside or behind it can support it, and its advance is controlled by an enemy pawn
+            let backward = !isolated && !supported && !phalanx
+                && [x - 1, x + 1].iter().all(|&x2| file_pawns(x2, player).all(ahead))
+                && (is_pawn(x - 1, y + 2 * forward, -player) || is_pawn(x + 1, y + 2 * forward, -player));
+            if isolated {
+                add(params.isolated, 1);
+            }
+            if backward {
+                add(params.backward, 1);
+            }
+            if supported || phalanx {
+                add(params.connected, 1);
+            }
+            if passed {
+                let rank = (if player.is_white() {y} else {7 - y}) as usize;
+                add([params.passed_mg[rank], params.passed_eg[rank]], 1);
+            }
+        }
+    }
+    score
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack_round_trip() {
+        for weights in [[[0, 0], [0, 0]], [[1, -1], [-250, 400]], [[i16::MAX >> 1, i16::MIN >> 1].map(Score::from), [-3, 7]]] {
+            assert_eq!(unpack(pack(weights)), weights);
+        }
+    }
+
+    #[test]
+    fn caches_zero_scores() {
+        let table = PawnTable::new(16);
+        let mut computed = 0;
+        for _ in 0..3 {
+            let weight = table.probe(12345, || {
+                computed += 1;
+                [[0, 0], [0, 0]]
+            });
+            assert_eq!(weight, [[0, 0], [0, 0]]);
+        }
+        assert_eq!(computed, 1);
+    }
+}
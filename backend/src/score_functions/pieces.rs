@@ -0,0 +1,74 @@
+use crate::types::*;
+use crate::game::all_coords;
+use super::side;
+use super::tapered::{EvalParams, Weight};
+
+// Number of safe squares at which knights, bishops, rooks and queens get no mobility score
+const MOBILITY_BASE: [Score; 4] = [4, 6, 6, 12];
+
+/*
+*   Piece activity of each side from its own point of view: mobility, the bishop pair, rooks on
+*   open files and the seventh rank, and knight outposts. A square counts for mobility if it isn't
+*   occupied by an own piece and can't be taken back by an enemy pawn.
+*/
+pub fn piece_activity(board: &Board, params: &EvalParams) -> [Weight; 2] {
+    let mut pawn_attacks = [[[false; 8]; 8]; 2];
+    for pos in all_coords().filter(|&pos| board[pos].is(PAWN)) {
+        for (x, y) in board.attacks_from(pos) {
+            pawn_attacks[side(board[pos].signum())][y][x] = true;
+        }
+    }
+    let pawns_on_file = |x: usize, owner: Player| (0..8).filter(move |&y| board[(x, y)] == PAWN * owner);
+
+    let mut score = [[0; 2]; 2];
+    for player in [1, -1] {
+        let mut add = |weight: Weight, times: Score| {
+            score[side(player)][0] += weight[0] * times;
+            score[side(player)][1] += weight[1] * times;
+        };
+        let relative_rank = |y: usize| if player.is_white() {y} else {7 - y};
+        let mut bishops = 0;
+        for pos in all_coords().filter(|&pos| player.can_control(board[pos])) {
+            let piece = board[pos].abs();
+            if !matches!(piece, KNIGHT | BISHOP | ROOK | QUEEN) {
+                continue;
+            }
+            let kind = piece as usize - KNIGHT as usize;
+            let mobility = board.attacks_from(pos).into_iter()
+                .filter(|&(x, y)| !player.can_control(board[(x, y)]) && !pawn_attacks[side(-player)][y][x])
+                .count() as Score;
+            add(params.mobility[kind], mobility - MOBILITY_BASE[kind]);
+
+            let (x, y) = pos;
+            match piece {
+                BISHOP => bishops += 1,
+                ROOK => {
+                    if pawns_on_file(x, player).next().is_none() {
+                        let open = pawns_on_file(x, -player).next().is_none();
+                        add(if open {params.rook_open_file} else {params.rook_semi_open_file}, 1);
+                    }
+                    // Only worth it when it has enemy pawns to attack or cuts off the king
+                    if relative_rank(y) == 6
+                        && (board.get_king_pos(-player).1 == (-player).back_rank() || (0..8).any(|x| board[(x, y)] == PAWN * -player)) {
+                        add(params.rook_seventh, 1);
+                    }
+                },
+                KNIGHT => {
+                    // Defended by a pawn and out of reach of the enemy pawns
+                    let rank = relative_rank(y);
+                    let reachable = [x.wrapping_sub(1), x + 1].into_iter().filter(|&x| x < 8).any(|x| {
+                        pawns_on_file(x, -player).any(|y2| relative_rank(y2) > rank)
+                    });
+                    if (3..=5).contains(&rank) && pawn_attacks[side(player)][y][x] && !reachable {
+                        add(params.knight_outpost, 1);
+                    }
+                },
+                _ => {}
+            }
+        }
+        if bishops >= 2 {
+            add(params.bishop_pair, 1);
+        }
+    }
+    score
+}
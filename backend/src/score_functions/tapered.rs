@@ -0,0 +1,484 @@
+use crate::types::*;
+use crate::game::*;
+use crate::zobrist::piece_key;
+use super::{Evaluator, EvalTrace, TraceTerm, hanging, side, net};
+use super::pawns::{PawnTable, pawn_structure};
+use super::king_safety::king_safety;
+use super::pieces::piece_activity;
+use super::endgame::endgame;
+
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use std::fs;
+
+// Contribution of each piece type to the game phase, the middlegame is at MAX_PHASE and above
+pub(super) const PHASE: [Score; 6] = [0, 1, 1, 2, 4, 0];
+const MAX_PHASE: Score = 24;
+const PAWN_TABLE_SLOTS: usize = 1 << 14;
+const TERMS: [&str; 6] = ["material", "placement", "pawn structure", "piece activity", "king safety", "hanging piece"];
+
+// Middlegame and endgame value of an evaluation term
+pub type Weight = [Score; 2];
+
+/*
+*   Material and piece-square tables with separate middlegame and endgame values, blended by
+*   the game phase. Indexed by piece type - 1, and by square from white's point of view with
+*   a8 first, so that the tables read like a board. The other terms are given per pawn.
+*   Values that are missing when deserializing keep their default.
+*/
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EvalParams {
+    pub material_mg: [Score; 6],
+    pub material_eg: [Score; 6],
+    #[serde(with = "tables")]
+    pub pst_mg: [[Score; 64]; 6],
+    #[serde(with = "tables")]
+    pub pst_eg: [[Score; 64]; 6],
+    pub doubled: Weight,
+    pub isolated: Weight,
+    pub backward: Weight,
+    // Defended by or side by side with another pawn
+    pub connected: Weight,
+    // Every island after the first
+    pub pawn_island: Weight,
+    // By rank from the pawn's own side
+    pub passed_mg: [Score; 8],
+    pub passed_eg: [Score; 8],
+    // King safety is scaled by the opponent's material rather than the game phase.
+    // Shield and storm are by distance from the king's rank to the pawn.
+    pub king_shield: [Score; 2],
+    pub king_storm: [Score; 3],
+    pub king_open_file: Score,
+    pub king_semi_open_file: Score,
+    // Per square attacked around the king, by piece type
+    pub king_attack_weight: [Score; 6],
+    // Percentage of the attack weight that counts, by the number of attacking pieces
+    pub king_attackers_scale: [Score; 8],
+    // Per safe square for knights, bishops, rooks and queens
+    pub mobility: [Weight; 4],
+    pub bishop_pair: Weight,
+    pub rook_open_file: Weight,
+    pub rook_semi_open_file: Weight,
+    pub rook_seventh: Weight,
+    pub knight_outpost: Weight,
+    // The material and score1 evaluators, the piece values also classify endgames for this one.
+    // Piece values are indexed by piece type - 1, the placement penalties are per knight or
+    // bishop on the edge and per pawn on a rook's file.
+    pub piece_values: [Score; 6],
+    pub bad_placement: Score,
+    pub rook_pawn_on_file: Score
+}
+
+impl Default for EvalParams {
+    fn default() -> EvalParams {
+        EvalParams {
+            material_mg: [82, 337, 365, 477, 1025, 0],
+            material_eg: [94, 281, 297, 512, 936, 0],
+            pst_mg: PST_MG,
+            pst_eg: PST_EG,
+            doubled: [-10, -20],
+            isolated: [-10, -15],
+            backward: [-8, -10],
+            connected: [6, 6],
+            pawn_island: [-5, -10],
+            passed_mg: [0, 0, 5, 10, 20, 35, 60, 0],
+            passed_eg: [0, 10, 15, 25, 45, 75, 120, 0],
+            king_shield: [12, 6],
+            king_storm: [-5, -15, -10],
+            king_open_file: -25,
+            king_semi_open_file: -15,
+            king_attack_weight: [0, 20, 20, 40, 80, 0],
+            king_attackers_scale: [0, 0, 50, 75, 88, 94, 97, 99],
+            mobility: [[4, 4], [5, 5], [2, 4], [1, 2]],
+            bishop_pair: [30, 50],
+            rook_open_file: [25, 10],
+            rook_semi_open_file: [12, 6],
+            rook_seventh: [20, 30],
+            knight_outpost: [20, 10],
+            piece_values: [100, 300, 300, 500, 900, 0],
+            bad_placement: -10,
+            rook_pawn_on_file: -10
+        }
+    }
+}
+
+impl EvalParams {
+    // Read from a TOML file if the name ends with .toml, JSON otherwise
+    pub fn from_file(path: &str) -> Result<EvalParams, String> {
+        let contents = fs::read_to_string(path).map_err(|err| format!("Could not read {path}: {err}"))?;
+        let params = if path.ends_with(".toml") {
+            toml::from_str(&contents).map_err(|err| err.to_string())
+        } else {
+            serde_json::from_str(&contents).map_err(|err| err.to_string())
+        };
+        params.map_err(|err| format!("Invalid evaluation parameters in {path}: {err}"))
+    }
+
+    // A copy with the values given in a JSON object replaced, for example {"bishop_pair": [40, 60]}
+    pub fn with_overrides(&self, json: &str) -> Result<EvalParams, String> {
+        let overrides: Value = serde_json::from_str(json).map_err(|err| err.to_string())?;
+        let Value::Object(overrides) = overrides else {
+            return Err("Expected an object of evaluation parameters".to_string());
+        };
+        let mut params = serde_json::to_value(self).map_err(|err| err.to_string())?;
+        for (name, value) in overrides {
+            match params.get_mut(&name) {
+                Some(param) => *param = value,
+                None => return Err(format!("Unknown evaluation parameter {name}"))
+            }
+        }
+        serde_json::from_value(params).map_err(|err| err.to_string())
+    }
+
+    // Every value that can change the evaluation, in a fixed order, for the tuner to adjust one at a time.
+    // Both sides always have a king, and pawns never stand on the first or last rank.
+    pub fn values_mut(&mut self) -> Vec<&mut Score> {
+        let mut values = Vec::new();
+        values.extend(self.material_mg[..KING as usize - 1].iter_mut());
+        values.extend(self.material_eg[..KING as usize - 1].iter_mut());
+        for table in [&mut self.pst_mg, &mut self.pst_eg] {
+            let (pawns, pieces) = table.split_at_mut(1);
+            values.extend(pawns[0][8..56].iter_mut());
+            values.extend(pieces.iter_mut().flatten());
+        }
+        values.extend(self.doubled.iter_mut());
+        values.extend(self.isolated.iter_mut());
+        values.extend(self.backward.iter_mut());
+        values.extend(self.connected.iter_mut());
+        values.extend(self.pawn_island.iter_mut());
+        values.extend(self.passed_mg[1..7].iter_mut());
+        values.extend(self.passed_eg[1..7].iter_mut());
+        values.extend(self.king_shield.iter_mut());
+        values.extend(self.king_storm.iter_mut());
+        values.push(&mut self.king_open_file);
+        values.push(&mut self.king_semi_open_file);
+        values.extend(self.king_attack_weight.iter_mut());
+        values.extend(self.king_attackers_scale.iter_mut());
+        values.extend(self.mobility.iter_mut().flatten());
+        values.extend(self.bishop_pair.iter_mut());
+        values.extend(self.rook_open_file.iter_mut());
+        values.extend(self.rook_semi_open_file.iter_mut());
+        values.extend(self.rook_seventh.iter_mut());
+        values.extend(self.knight_outpost.iter_mut());
+        values
+    }
+}
+
+// Serde only implements arrays up to 32 elements, so the piece-square tables go through vectors
+mod tables {
+    use crate::types::Score;
+    use serde::{Serialize, Deserialize, Serializer, Deserializer};
+    use serde::de::Error;
+
+    pub fn serialize<S: Serializer>(tables: &[[Score; 64]; 6], serializer: S) -> Result<S::Ok, S::Error> {
+        let tables: Vec<&[Score]> = tables.iter().map(|table| &table[..]).collect();
+        tables.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[[Score; 64]; 6], D::Error> {
+        let tables: Vec<Vec<Score>> = Vec::deserialize(deserializer)?;
+        let tables: Vec<[Score; 64]> = tables.into_iter()
+            .map(|table| table.try_into().map_err(|_| D::Error::custom("expected 64 squares per table")))
+            .collect::<Result<_, _>>()?;
+        tables.try_into().map_err(|_| D::Error::custom("expected 6 piece-square tables"))
+    }
+}
+
+// Tapered evaluation, together with the hanging piece term of score1 and endgame knowledge
+#[derive(Debug)]
+pub struct Tapered {
+    params: EvalParams,
+    pawn_table: PawnTable
+}
+
+impl Default for Tapered {
+    fn default() -> Tapered {
+        Tapered::new(EvalParams::default())
+    }
+}
+
+// Material and piece-square values of each side from its own point of view
+struct PieceSums {
+    material: [Weight; 2],
+    placement: [Weight; 2],
+    phase: Score,
+    pawn_key: u64
+}
+
+impl Tapered {
+    pub fn new(params: EvalParams) -> Tapered {
+        Tapered { params, pawn_table: PawnTable::new(PAWN_TABLE_SLOTS) }
+    }
+
+    fn piece_sums(&self, board: &Board) -> PieceSums {
+        let params = &self.params;
+        let mut sums = PieceSums { material: [[0; 2]; 2], placement: [[0; 2]; 2], phase: 0, pawn_key: 0 };
+        for pos in all_coords().filter(|&pos| !board[pos].is_empty()) {
+            let piece = board[pos];
+            if piece.is(PAWN) {
+                sums.pawn_key ^= piece_key(piece, pos);
+            }
+            let kind = piece.unsigned_abs() as usize - 1;
+            let square = pst_square(piece, pos);
+            let side = side(piece.signum());
+            sums.material[side][0] += params.material_mg[kind];
+            sums.material[side][1] += params.material_eg[kind];
+            sums.placement[side][0] += params.pst_mg[kind][square];
+            sums.placement[side][1] += params.pst_eg[kind][square];
+            sums.phase += PHASE[kind];
+        }
+        sums.phase = sums.phase.min(MAX_PHASE);
+        sums
+    }
+
+    // Material values at the game phase, for the hanging piece term
+    fn piece_values(&self, phase: Score) -> [Score; 6] {
+        let params = &self.params;
+        std::array::from_fn(|kind| taper([params.material_mg[kind], params.material_eg[kind]], phase))
+    }
+
+    // Every term for each side from its own point of view, in the order of TERMS. Each side is
+    // tapered on its own so that the evaluation is exactly the sum of what the trace shows.
+    fn terms(&self, board: &Board, player: Player) -> [[Score; 2]; 6] {
+        let params = &self.params;
+        let sums = self.piece_sums(board);
+        let pawns = self.pawn_table.probe(sums.pawn_key, || pawn_structure(board, params));
+        let tapered = |weights: [Weight; 2]| weights.map(|weight| taper(weight, sums.phase));
+        [
+            tapered(sums.material),
+            tapered(sums.placement),
+            tapered(pawns),
+            tapered(piece_activity(board, params)),
+            king_safety(board, params),
+            hanging(board, player, &self.piece_values(sums.phase))
+        ]
+    }
+}
+
+impl Evaluator for Tapered {
+    fn evaluate(&self, board: &Board, player: Player) -> Score {
+        let score = self.terms(board, player).into_iter().map(net).sum();
+        endgame(board, score, &self.params.piece_values)
+    }
+
+    fn trace(&self, board: &Board, player: Player) -> EvalTrace {
+        let terms = self.terms(board, player);
+        let score: Score = terms.into_iter().map(net).sum();
+        // Endgame scaling and mop-up count for the side they favour
+        let endgame = endgame(board, score, &self.params.piece_values) - score;
+        let terms = TERMS.into_iter().zip(terms)
+            .map(|(name, term)| TraceTerm::new(name, term))
+            .chain([TraceTerm::new("endgame", [endgame.max(0), (-endgame).max(0)])])
+            .collect();
+        EvalTrace::new(terms, self.evaluate(board, player))
+    }
+}
+
+// Blend the middlegame and endgame values by the game phase
+fn taper([mg, eg]: Weight, phase: Score) -> Score {
+    (mg * phase + eg * (MAX_PHASE - phase)) / MAX_PHASE
+}
+
+// Black's tables are white's mirrored vertically
+fn pst_square(piece: Piece, (x, y): Position) -> usize {
+    if piece.is_white() {
+        (7 - y) * 8 + x
+    } else {
+        y * 8 + x
+    }
+}
+
+#[rustfmt::skip]
+const PST_MG: [[Score; 64]; 6] = [
+    // Pawn
+    [
+          0,   0,   0,   0,   0,   0,   0,   0,
+         98, 134,  61,  95,  68, 126,  34, -11,
+         -6,   7,  26,  31,  65,  56,  25, -20,
+        -14,  13,   6,  21,  23,  12,  17, -23,
+        -27,  -2,  -5,  12,  17,   6,  10, -25,
+        -26,  -4,  -4, -10,   3,   3,  33, -12,
+        -35,  -1, -20, -23, -15,  24,  38, -22,
+          0,   0,   0,   0,   0,   0,   0,   0
+    ],
+    // Knight
+    [
+       -167, -89, -34, -49,  61, -97, -15,-107,
+        -73, -41,  72,  36,  23,  62,   7, -17,
+        -47,  60,  37,  65,  84, 129,  73,  44,
+         -9,  17,  19,  53,  37,  69,  18,  22,
+        -13,   4,  16,  13,  28,  19,  21,  -8,
+        -23,  -9,  12,  10,  19,  17,  25, -16,
+        -29, -53, -12,  -3,  -1,  18, -14, -19,
+       -105, -21, -58, -33, -17, -28, -19, -23
+    ],
+    // Bishop
+    [
+        -29,   4, -82, -37, -25, -42,   7,  -8,
+        -26,  16, -18, -13,  30,  59,  18, -47,
+        -16,  37,  43,  40,  35,  50,  37,  -2,
+         -4,   5,  19,  50,  37,  37,   7,  -2,
+         -6,  13,  13,  26,  34,  12,  10,   4,
+          0,  15,  15,  15,  14,  27,  18,  10,
+          4,  15,  16,   0,   7,  21,  33,   1,
+        -33,  -3, -14, -21, -13, -12, -39, -21
+    ],
+    // Rook
+    [
+         32,  42,  32,  51,  63,   9,  31,  43,
+         27,  32,  58,  62,  80,  67,  26,  44,
+         -5,  19,  26,  36,  17,  45,  61,  16,
+        -24, -11,   7,  26,  24,  35,  -8, -20,
+        -36, -26, -12,  -1,   9,  -7,   6, -23,
+        -45, -25, -16, -17,   3,   0,  -5, -33,
+        -44, -16, -20,  -9,  -1,  11,  -6, -71,
+        -19, -13,   1,  17,  16,   7, -37, -26
+    ],
+    // Queen
+    [
+        -28,   0,  29,  12,  59,  44,  43,  45,
+        -24, -39,  -5,   1, -16,  57,  28,  54,
+        -13, -17,   7,   8,  29,  56,  47,  57,
+        -27, -27, -16, -16,  -1,  17,  -2,   1,
+         -9, -26,  -9, -10,  -2,  -4,   3,  -3,
+        -14,   2, -11,  -2,  -5,   2,  14,   5,
+        -35,  -8,  11,   2,   8,  15,  -3,   1,
+         -1, -18,  -9,  10, -15, -25, -31, -50
+    ],
+    // King
+    [
+        -65,  23,  16, -15, -56, -34,   2,  13,
+         29,  -1, -20,  -7,  -8,  -4, -38, -29,
+         -9,  24,   2, -16, -20,   6,  22, -22,
+        -17, -20, -12, -27, -30, -25, -14, -36,
+        -49,  -1, -27, -39, -46, -44, -33, -51,
+        -14, -14, -22, -46, -44, -30, -15, -27,
+          1,   7,  -8, -64, -43, -16,   9,   8,
+        -15,  36,  12, -54,   8, -28,  24,  14
+    ]
+];
+
+#[rustfmt::skip]
+const PST_EG: [[Score; 64]; 6] = [
+    // Pawn
+    [
+          0,   0,   0,   0,   0,   0,   0,   0,
+        178, 173, 158, 134, 147, 132, 165, 187,
+         94, 100,  85,  67,  56,  53,  82,  84,
+         32,  24,  13,   5,  -2,   4,  17,  17,
+         13,   9,  -3,  -7,  -7,  -8,   3,  -1,
+          4,   7,  -6,   1,   0,  -5,  -1,  -8,
+         13,   8,   8,  10,  13,   0,   2,  -7,
+          0,   0,   0,   0,   0,   0,   0,   0
+    ],
+    // Knight
+    [
+        -58, -38, -13, -28, -31, -27, -63, -99,
+        -25,  -8, -25,  -2,  -9, -25, -24, -52,
+        -24, -20,  10,   9,  -1,  -9, -19, -41,
+        -17,   3,  22,  22,  22,  11,   8, -18,
+        -18,  -6,  16,  25,  16,  17,   4, -18,
+        -23,  -3,  -1,  15,  10,  -3, -20, -22,
+        -42, -20, -10,  -5,  -2, -20, -23, -44,
+        -29, -51, -23, -15, -22, -18, -50, -64
+    ],
+    // Bishop
+    [
+        -14, -21, -11,  -8,  -7,  -9, -17, -24,
+         -8,  -4,   7, -12,  -3, -13,  -4, -14,
+          2,  -8,   0,  -1,  -2,   6,   0,   4,
+         -3,   9,  12,   9,  14,  10,   3,   2,
+         -6,   3,  13,  19,   7,  10,  -3,  -9,
+        -12,  -3,   8,  10,  13,   3,  -7, -15,
+        -14, -18,  -7,  -1,   4,  -9, -15, -27,
+        -23,  -9, -23,  -5,  -9, -16,  -5, -17
+    ],
+    // Rook
+    [
+         13,  10,  18,  15,  12,  12,   8,   5,
+         11,  13,  13,  11,  -3,   3,   8,   3,
+          7,   7,   7,   5,   4,  -3,  -5,  -3,
+          4,   3,  13,   1,   2,   1,  -1,   2,
+          3,   5,   8,   4,  -5,  -6,  -8, -11,
+         -4,   0,  -5,  -1,  -7, -12,  -8, -16,
+         -6,  -6,   0,   2,  -9,  -9, -11,  -3,
+         -9,   2,   3,  -1,  -5, -13,   4, -20
+    ],
+    // Queen
+    [
+         -9,  22,  22,  27,  27,  19,  10,  20,
+        -17,  20,  32,  41,  58,  25,  30,   0,
+        -20,   6,   9,  49,  47,  35,  19,   9,
+          3,  22,  24,  45,  57,  40,  57,  36,
+        -18,  28,  19,  47,  31,  34,  39,  23,
+        -16, -27,  15,   6,   9,  17,  10,   5,
+        -22, -23, -30, -16, -16, -23, -36, -32,
+        -33, -28, -22, -43,  -5, -32, -20, -41
+    ],
+    // King
+    [
+        -74, -35, -18, -18, -11,  15,   4, -17,
+        -12,  17,  14,  17,  17,  38,  23,  11,
+         10,  17,  23,  15,  20,  45,  44,  13,
+         -8,  22,  24,  27,  26,  33,  26,   3,
+        -18,  -4,  21,  24,  27,  23,   9, -11,
+        -19,  -3,  11,  21,  23,  16,   7,  -9,
+        -27, -11,   4,  13,  14,   4,  -5, -17,
+        -53, -34, -21, -11, -28, -14, -24, -43
+    ]
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::parse_fen;
+
+    #[test]
+    fn overrides_replace_only_the_given_values() {
+        let base = EvalParams { doubled: [-1, -2], ..Default::default() };
+        let params = base.with_overrides(r#"{"bishop_pair": [40, 60], "piece_values": [100, 320, 330, 500, 950, 0]}"#).unwrap();
+        assert_eq!(params.bishop_pair, [40, 60]);
+        assert_eq!(params.piece_values, [100, 320, 330, 500, 950, 0]);
+        assert_eq!(params.doubled, [-1, -2]);
+
+        let mut expected = serde_json::to_value(&base).unwrap();
+        expected["bishop_pair"] = serde_json::json!([40, 60]);
+        expected["piece_values"] = serde_json::json!([100, 320, 330, 500, 950, 0]);
+        assert_eq!(serde_json::to_value(&params).unwrap(), expected);
+    }
+
+    #[test]
+    fn empty_overrides_change_nothing() {
+        let params = EvalParams::default().with_overrides("{}").unwrap();
+        assert_eq!(serde_json::to_value(&params).unwrap(), serde_json::to_value(EvalParams::default()).unwrap());
+    }
+
+    #[test]
+    fn invalid_overrides() {
+        let params = EvalParams::default();
+        assert_eq!(params.with_overrides(r#"{"bishop_pear": [40, 60]}"#).unwrap_err(), "Unknown evaluation parameter bishop_pear");
+        assert!(params.with_overrides(r#"{"bishop_pair": 40}"#).is_err());
+        assert!(params.with_overrides(r#"{"piece_values": [100, 300]}"#).is_err());
+        assert!(params.with_overrides("[40, 60]").is_err());
+        assert!(params.with_overrides("bishop_pair=40").is_err());
+    }
+
+    // Middlegame, opposite bishops, mop-up in KRK, a hanging queen and a pawn ending
+    #[test]
+    fn trace_adds_up_to_the_evaluation() {
+        let evaluator = Tapered::default();
+        for fen in [
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "4k3/5p2/2b5/8/3B4/8/5PP1/6K1 b - - 0 1",
+            "8/8/8/3k4/8/8/8/R3K3 w - - 0 1",
+            "rnb1kbnr/pppp1ppp/8/4p1q1/3P4/8/PPP1PPPP/RNBQKBNR w KQkq - 0 3",
+            "8/8/8/2Kp4/3Pk3/8/8/8 b - - 0 1"
+        ] {
+            let data = parse_fen(fen).unwrap();
+            let trace = evaluator.trace(&data.board, data.player);
+            assert_eq!(trace.total, evaluator.evaluate(&data.board, data.player), "{fen}");
+            assert_eq!(trace.sum, trace.total, "{fen}: {trace:?}");
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use crate::types::*;
+
+use rand::Rng;
+
+pub const MAX_SKILL_LEVEL: u8 = 20;
+pub const MIN_ELO: u32 = 800;
+const ELO_PER_LEVEL: u32 = 70;
+pub const MAX_ELO: u32 = MIN_ELO + ELO_PER_LEVEL * MAX_SKILL_LEVEL as u32;
+
+/*
+*   Playing strength from 0 (beginner) to MAX_SKILL_LEVEL (full strength). Weaker levels search
+*   less deep, see a noisy evaluation and pick randomly among the best few moves, preferring
+*   moves that are close in score to the best one.
+*/
+#[derive(Clone, Copy, Debug)]
+pub struct Skill {
+    level: u8
+}
+
+impl Skill {
+    pub fn new(level: u8) -> Skill {
+        Skill { level: level.min(MAX_SKILL_LEVEL) }
+    }
+
+    // Rough mapping where level 0 plays around 800 and full strength around 2200
+    pub fn from_elo(elo: u32) -> Skill {
+        Skill::new((elo.saturating_sub(MIN_ELO) / ELO_PER_LEVEL).min(MAX_SKILL_LEVEL as u32) as u8)
+    }
+
+    pub fn is_full_strength(&self) -> bool {
+        self.level == MAX_SKILL_LEVEL
+    }
+
+    fn weakness(&self) -> Score {
+        (MAX_SKILL_LEVEL - self.level) as Score
+    }
+
+    pub fn max_depth(&self) -> usize {
+        if self.is_full_strength() {usize::MAX} else {1 + self.level as usize / 4}
+    }
+
+    // Number of lines to choose the move from
+    pub fn multi_pv(&self) -> usize {
+        if self.is_full_strength() {1} else {4}
+    }
+
+    // Largest error added to the evaluation, in centipawns
+    pub fn eval_noise(&self) -> Score {
+        self.weakness() * 10
+    }
+
+    // Pick one of the lines, giving every line a random bonus of up to 15 centipawns per level of weakness
+    pub fn pick_move(&self, lines: &[PvLine], player: Player, rng: &mut impl Rng) -> Option<Move> {
+        let spread = self.weakness() * 15;
+        lines.iter()
+            .max_by_key(|line| line.score * player as Score + rng.gen_range(0..=spread))
+            .map(|line| line.moves[0])
+    }
+}
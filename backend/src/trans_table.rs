@@ -0,0 +1,193 @@
+use crate::types::*;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bound {
+    Exact,
+    Lower,
+    Upper
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TransEntry {
+    pub score: Score,
+    pub depth: usize,
+    pub bound: Bound,
+    pub best_move: Option<Move>
+}
+
+#[derive(Default)]
+struct Slot {
+    key: AtomicU64,
+    data: AtomicU64
+}
+
+/*
+*   Transposition table that can be shared between search threads without locking.
+*   Each slot stores the hash xor:ed with the data, so a slot that was torn by two
+*   threads writing at the same time simply fails the key check when probed.
+*/
+pub struct TransTable {
+    slots: Vec<Slot>,
+    mask: usize
+}
+
+impl TransTable {
+    pub fn new(size_mb: usize) -> TransTable {
+        let num_slots = (size_mb * 1024 * 1024 / std::mem::size_of::<Slot>()).max(1);
+        // Round down to a power of two so that the hash can be masked into an index
+        let num_slots = 1 << (usize::BITS - 1 - num_slots.leading_zeros());
+        TransTable {
+            slots: (0..num_slots).map(|_| Slot::default()).collect(),
+            mask: num_slots - 1
+        }
+    }
+
+    // Mate scores are stored relative to the node rather than the root, since the same
+    // position can be reached at a different ply. `ply` converts them back and forth.
+    pub fn probe(&self, hash: u64, ply: usize) -> Option<TransEntry> {
+        let slot = &self.slots[hash as usize & self.mask];
+        let data = slot.data.load(Ordering::Relaxed);
+        if slot.key.load(Ordering::Relaxed) ^ data != hash || data == 0 {
+            return None;
+        }
+        let mut entry = unpack(data);
+        entry.score = shift_mate(entry.score, -(ply as Score));
+        Some(entry)
+    }
+
+    pub fn store(&self, hash: u64, ply: usize, mut entry: TransEntry) {
+        // Keep deeper results for the same position
+        if matches!(self.probe(hash, ply), Some(old) if old.depth > entry.depth) {
+            return;
+        }
+        entry.score = shift_mate(entry.score, ply as Score);
+        let slot = &self.slots[hash as usize & self.mask];
+        let data = pack(entry);
+        slot.key.store(hash ^ data, Ordering::Relaxed);
+        slot.data.store(data, Ordering::Relaxed);
+    }
+}
+
+// Moves the ply a mate score counts from by `plies`, other scores are left alone
+pub fn shift_mate(score: Score, plies: Score) -> Score {
+    if score > SCORE_MATE_BOUND {
+        score + plies
+    } else if score < -SCORE_MATE_BOUND {
+        score - plies
+    } else {
+        score
+    }
+}
+
+impl std::fmt::Debug for TransTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "TransTable {{ slots: {} }}", self.slots.len())
+    }
+}
+
+/*
+*   Data layout:
+*   bits  0..32 score
+*   bits 32..40 depth
+*   bits 40..42 bound
+*   bit  42     has move
+*   bits 43..49 move from square
+*   bits 49..55 move to square
+*/
+fn pack(entry: TransEntry) -> u64 {
+    let bound = match entry.bound {
+        Bound::Exact => 0,
+        Bound::Lower => 1,
+        Bound::Upper => 2
+    };
+    let mut data = entry.score as u32 as u64
+        | (entry.depth.min(255) as u64) << 32
+        | bound << 40;
+    if let Some((from, to)) = entry.best_move {
+        data |= 1 << 42
+            | ((from.1 * 8 + from.0) as u64) << 43
+            | ((to.1 * 8 + to.0) as u64) << 49;
+    }
+    data
+}
+
+fn unpack(data: u64) -> TransEntry {
+    let bound = match (data >> 40) & 0x3 {
+        0 => Bound::Exact,
+        1 => Bound::Lower,
+        _ => Bound::Upper
+    };
+    let square = |shift: u64| {
+        let index = ((data >> shift) & 0x3f) as usize;
+        (index % 8, index / 8)
+    };
+    TransEntry {
+        score: data as u32 as Score,
+        depth: ((data >> 32) & 0xff) as usize,
+        bound,
+        best_move: (data & 1 << 42 != 0).then(|| (square(43), square(49)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_same(a: TransEntry, b: TransEntry) {
+        assert_eq!((a.score, a.depth, a.bound, a.best_move), (b.score, b.depth, b.bound, b.best_move));
+    }
+
+    #[test]
+    fn pack_round_trip() {
+        let entries = [
+            TransEntry { score: 0, depth: 1, bound: Bound::Exact, best_move: None },
+            TransEntry { score: -250, depth: 12, bound: Bound::Lower, best_move: Some(((0, 0), (7, 7))) },
+            TransEntry { score: SCORE_MATE - 3, depth: 255, bound: Bound::Upper, best_move: Some(((4, 1), (4, 3))) },
+            TransEntry { score: -SCORE_INF, depth: 0, bound: Bound::Upper, best_move: Some(((7, 6), (6, 7))) }
+        ];
+        for entry in entries {
+            assert_same(unpack(pack(entry)), entry);
+        }
+    }
+
+    #[test]
+    fn depth_is_capped() {
+        let entry = TransEntry { score: 10, depth: 300, bound: Bound::Exact, best_move: None };
+        assert_eq!(unpack(pack(entry)).depth, 255);
+    }
+
+    #[test]
+    fn keeps_the_deeper_entry() {
+        let table = TransTable::new(1);
+        let deep = TransEntry { score: 40, depth: 8, bound: Bound::Exact, best_move: Some(((1, 0), (2, 2))) };
+        table.store(12345, 0, deep);
+        table.store(12345, 0, TransEntry { score: -40, depth: 3, bound: Bound::Lower, best_move: None });
+        assert_same(table.probe(12345, 0).unwrap(), deep);
+        assert!(table.probe(54321, 0).is_none());
+    }
+
+    #[test]
+    fn mate_scores_follow_the_ply() {
+        let table = TransTable::new(1);
+        // Mate two plies after a node at ply 3, so five plies from the root
+        table.store(1, 3, TransEntry { score: SCORE_MATE - 5, depth: 4, bound: Bound::Exact, best_move: None });
+        table.store(2, 3, TransEntry { score: -(SCORE_MATE - 5), depth: 4, bound: Bound::Exact, best_move: None });
+        table.store(3, 3, TransEntry { score: 120, depth: 4, bound: Bound::Exact, best_move: None });
+        // Reached at ply 7 the same mate is nine plies from the root
+        assert_eq!(table.probe(1, 7).unwrap().score, SCORE_MATE - 9);
+        assert_eq!(table.probe(2, 7).unwrap().score, -(SCORE_MATE - 9));
+        assert_eq!(table.probe(1, 1).unwrap().score, SCORE_MATE - 3);
+        assert_eq!(table.probe(3, 7).unwrap().score, 120);
+    }
+
+    #[test]
+    fn shift_mate_leaves_other_scores() {
+        assert_eq!(shift_mate(SCORE_MATE - 10, 4), SCORE_MATE - 6);
+        assert_eq!(shift_mate(-(SCORE_MATE - 10), 4), -(SCORE_MATE - 6));
+        assert_eq!(shift_mate(SCORE_MATE_BOUND, 4), SCORE_MATE_BOUND);
+        assert_eq!(shift_mate(0, 4), 0);
+        assert_eq!(shift_mate(shift_mate(SCORE_MATE - 10, 4), -4), SCORE_MATE - 10);
+    }
+}
@@ -0,0 +1,163 @@
+use crate::types::*;
+use crate::utils::parse_fen;
+use crate::score_functions::{Evaluator, EvalParams, Tapered};
+
+use std::fs;
+use std::thread;
+
+/*
+*   Texel tuning of the tapered evaluation. Every position is labelled with the result of the
+*   game it was taken from, and the error is the mean squared difference between the results
+*   and the evaluations mapped to an expected result by a sigmoid. The evaluation is used
+*   without a search, so the positions should be quiet.
+*/
+
+// Game result from white's point of view: 1 for a win, 0.5 for a draw and 0 for a loss
+pub struct LabelledPosition {
+    board: Board,
+    player: Player,
+    result: f64
+}
+
+/*
+*   Parse a FEN followed by the result, for example "<fen> 1-0", "<fen>; 0.5" or the EPD style
+*   "<fen> c9 \"1/2-1/2\";". The move counters may be left out of the FEN.
+*/
+pub fn parse_position(line: &str) -> Result<LabelledPosition, String> {
+    let line = line.replace(';', " ");
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    let (result, tokens) = tokens.split_last().ok_or("Empty line")?;
+    let result = parse_result(result.trim_matches(|ch| matches!(ch, '"' | '[' | ']')))
+        .ok_or_else(|| format!("Invalid result {result}"))?;
+    if tokens.len() < 4 {
+        return Err("Incomplete FEN".to_string());
+    }
+    let counters = tokens[4..].iter().take(2).take_while(|token| token.parse::<usize>().is_ok());
+    let mut fen = tokens[..4].join(" ");
+    match counters.count() {
+        0 => fen.push_str(" 0 1"),
+        1 => fen = format!("{} {} 1", fen, tokens[4]),
+        _ => fen = format!("{} {} {}", fen, tokens[4], tokens[5])
+    }
+    let data = parse_fen(&fen).map_err(|err| err.0.unwrap_or("Invalid FEN").to_string())?;
+    Ok(LabelledPosition { board: data.board, player: data.player, result })
+}
+
+fn parse_result(result: &str) -> Option<f64> {
+    match result {
+        "1-0" => Some(1.0),
+        "0-1" => Some(0.0),
+        "1/2-1/2" | "1/2" => Some(0.5),
+        _ => result.parse().ok().filter(|result| (0.0..=1.0).contains(result))
+    }
+}
+
+// One position per line, empty lines and lines starting with # are skipped
+pub fn load_positions(path: &str) -> Result<Vec<LabelledPosition>, String> {
+    let contents = fs::read_to_string(path).map_err(|err| format!("Could not read {path}: {err}"))?;
+    contents.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| parse_position(line).map_err(|err| format!("{path}:{}: {err}", i + 1)))
+        .collect()
+}
+
+// Expected result for white, `k` scales centipawns to winning chances
+fn sigmoid(score: Score, k: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf(-k * score as f64 / 400.0))
+}
+
+fn mean_error(positions: &[LabelledPosition], scores: &[Score], k: f64) -> f64 {
+    let total: f64 = positions.iter()
+        .zip(scores)
+        .map(|(position, &score)| (position.result - sigmoid(score, k)).powi(2))
+        .sum();
+    total / positions.len().max(1) as f64
+}
+
+// Evaluations of all positions, split between the available cores
+fn evaluate_all(positions: &[LabelledPosition], params: &EvalParams) -> Vec<Score> {
+    let evaluator = Tapered::new(params.clone());
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = positions.len().div_ceil(threads).max(1);
+    thread::scope(|scope| {
+        let handles: Vec<_> = positions.chunks(chunk_size).map(|chunk| {
+            let evaluator = &evaluator;
+            scope.spawn(move || {
+                chunk.iter().map(|position| evaluator.evaluate(&position.board, position.player)).collect::<Vec<_>>()
+            })
+        }).collect();
+        handles.into_iter().flat_map(|handle| handle.join().expect("Evaluation thread panicked")).collect()
+    })
+}
+
+pub struct Tuner {
+    positions: Vec<LabelledPosition>,
+    params: EvalParams,
+    k: f64,
+    error: f64
+}
+
+impl Tuner {
+    // Fits the sigmoid to the starting parameters, `k` then stays fixed while tuning
+    pub fn new(positions: Vec<LabelledPosition>, params: EvalParams) -> Tuner {
+        let scores = evaluate_all(&positions, &params);
+        let k = fit_k(&positions, &scores);
+        let error = mean_error(&positions, &scores, k);
+        Tuner { positions, params, k, error }
+    }
+
+    pub fn params(&self) -> &EvalParams {
+        &self.params
+    }
+
+    pub fn k(&self) -> f64 {
+        self.k
+    }
+
+    pub fn error(&self) -> f64 {
+        self.error
+    }
+
+    fn error_with(&self, params: &EvalParams) -> f64 {
+        mean_error(&self.positions, &evaluate_all(&self.positions, params), self.k)
+    }
+
+    /*
+    *   Try moving every value up and then down by `step`, keeping the changes that lower the
+    *   error. Returns whether anything changed, otherwise the parameters are at a local minimum
+    *   for this step size.
+    */
+    pub fn pass(&mut self, step: Score) -> bool {
+        let num_values = self.params.values_mut().len();
+        let mut improved = false;
+        for i in 0..num_values {
+            for delta in [step, -step] {
+                let mut candidate = self.params.clone();
+                *candidate.values_mut()[i] += delta;
+                let error = self.error_with(&candidate);
+                if error < self.error {
+                    self.params = candidate;
+                    self.error = error;
+                    improved = true;
+                    break;
+                }
+            }
+        }
+        improved
+    }
+}
+
+// The error has a single minimum in k in practice, so a ternary search finds the best fit
+fn fit_k(positions: &[LabelledPosition], scores: &[Score]) -> f64 {
+    let (mut low, mut high) = (0.0, 10.0);
+    for _ in 0..100 {
+        let third = (high - low) / 3.0;
+        if mean_error(positions, scores, low + third) < mean_error(positions, scores, high - third) {
+            high -= third;
+        } else {
+            low += third;
+        }
+    }
+    (low + high) / 2.0
+}
@@ -1,12 +1,18 @@
 use rocket::response::status;
 use rand::seq::SliceRandom;
+use crate::utils::seeded_rng;
+use crate::score_functions::Evaluator;
+use serde::Serialize;
 use std::ops::{Index, IndexMut};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 pub type BadRequest = status::BadRequest<&'static str>;
 
 pub type Position = (usize, usize);
 pub type IPos = (i8, i8);
 pub type Move = (Position, Position);
-pub type Score = f64;
+pub type Score = i32;
 pub type Player = i8;
 pub type Piece = i8;
 
@@ -20,18 +26,28 @@ pub const KING: Piece = 6;
 //pub const LOWER_RIGHT: Position = (7, 0);
 //pub const UPPER_LEFT: Position = (0, 7);
 //pub const LOWER_LEFT: Position = (0, 0);
-pub const SCORE_ERR: Score = 0.001;
+
+// Scores are in centipawns from white's point of view. A side that is mated `ply` plies from
+// the root scores SCORE_MATE - ply against it, so anything beyond SCORE_MATE_BOUND is a mate.
+pub const SCORE_INF: Score = 30000;
+pub const SCORE_MATE: Score = 29000;
+pub const SCORE_MATE_BOUND: Score = SCORE_MATE - 1000;
+
+pub const START_FEN: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+pub const DEFAULT_DEPTH: usize = 6;
+// Upper limit on the search threads a caller can ask for
+pub const MAX_THREADS: usize = 256;
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Board(pub [[Piece; 8]; 8]);
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct CastleStatus {
     pub white: PlayerCastleStatus,
     pub black: PlayerCastleStatus
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct PlayerCastleStatus {
     pub k: bool,
     pub q: bool
@@ -41,6 +57,10 @@ impl CastleStatus {
     pub fn default(status: bool) -> CastleStatus {
         CastleStatus {white: PlayerCastleStatus {k: status, q: status}, black: PlayerCastleStatus {k: status, q: status}}
     }
+
+    pub fn flipped(&self) -> CastleStatus {
+        CastleStatus {white: self.black.clone(), black: self.white.clone()}
+    }
 }
 
 impl PlayerCastleStatus {
@@ -121,6 +141,7 @@ impl IndexMut<char> for CastleStatus {
     }
 }
 
+#[derive(Clone, Debug)]
 pub struct FenData {
     pub board: Board,
     pub player: Player,
@@ -130,21 +151,203 @@ pub struct FenData {
     pub move_number: usize
 }
 
+impl FenData {
+    // The same position with the colours swapped and the board mirrored top to bottom
+    pub fn flipped(&self) -> FenData {
+        FenData {
+            board: self.board.flipped(),
+            player: -self.player,
+            can_castle: self.can_castle.flipped(),
+            en_passant: self.en_passant.map(|(x, y)| (x, 7 - y)),
+            half_moves: self.half_moves,
+            move_number: self.move_number
+        }
+    }
+}
+
+// A root move with its score and the expected continuation, starting with the root move itself
+#[derive(Clone, Debug)]
+pub struct PvLine {
+    pub score: Score,
+    pub moves: Vec<Move>
+}
+
+// Counters collected during a search
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct SearchStats {
+    // All nodes visited, including leaf nodes
+    pub nodes: usize,
+    // Nodes at the search horizon, which are evaluated statically
+    pub leaf_nodes: usize,
+    pub tt_hits: usize,
+    pub tt_cutoffs: usize,
+    // Deepest completed iteration
+    pub depth: usize,
+    // Deepest ply reached, including extensions
+    pub seldepth: usize,
+    pub time_ms: u64,
+    pub nps: u64,
+    // Nodes of the last completed iteration relative to the one before it
+    pub branching_factor: f64
+}
+
+impl SearchStats {
+    // Add the counters of another thread searching the same position
+    pub fn merge(&mut self, other: &SearchStats) {
+        self.nodes += other.nodes;
+        self.leaf_nodes += other.leaf_nodes;
+        self.tt_hits += other.tt_hits;
+        self.tt_cutoffs += other.tt_cutoffs;
+        self.seldepth = self.seldepth.max(other.seldepth);
+    }
+
+    pub fn set_time(&mut self, elapsed: Duration) {
+        self.time_ms = elapsed.as_millis() as u64;
+        self.nps = (self.nodes as f64 / elapsed.as_secs_f64().max(1e-3)) as u64;
+    }
+}
+
+/*
+*   Shared signal for aborting a search. It is raised either from the outside with stop(),
+*   or by the search itself once the node or time limit has been reached.
+*/
+#[derive(Clone, Debug, Default)]
+pub struct SearchStop {
+    flag: Arc<AtomicBool>,
+    parent: Option<Arc<AtomicBool>>,
+    nodes: Arc<AtomicUsize>,
+    max_nodes: Option<usize>,
+    deadline: Option<Instant>
+}
+
+impl SearchStop {
+    // A signal for a single search that starts now. It is also raised by stopping `self`,
+    // but stopping it doesn't stop `self`, so the caller's signal can be used for several searches.
+    pub fn with_limits(&self, max_nodes: Option<usize>, movetime: Option<Duration>) -> SearchStop {
+        SearchStop {
+            parent: Some(self.flag.clone()),
+            max_nodes,
+            deadline: movetime.map(|movetime| Instant::now() + movetime),
+            ..SearchStop::default()
+        }
+    }
+
+    pub fn stop(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.flag.load(Ordering::Relaxed) || self.parent.as_ref().is_some_and(|parent| parent.load(Ordering::Relaxed))
+    }
+
+    // Nodes reported so far by all threads sharing the signal
+    pub fn nodes(&self) -> usize {
+        self.nodes.load(Ordering::Relaxed)
+    }
+
+    // Report searched nodes, raising the flag if a limit has been reached
+    pub fn add_nodes(&self, nodes: usize) {
+        let total = self.nodes.fetch_add(nodes, Ordering::Relaxed) + nodes;
+        if self.max_nodes.is_some_and(|max_nodes| total >= max_nodes) || self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            self.stop();
+        }
+    }
+}
+
+// What a single search may spend. The search ends at `depth` or when any of the other limits is reached.
+#[derive(Clone, Debug)]
+pub struct SearchLimits {
+    pub depth: usize,
+    pub nodes: Option<usize>,
+    pub movetime: Option<Duration>,
+    // Left to the engine when not given
+    pub threads: Option<usize>,
+    // Number of best lines to report
+    pub multi_pv: usize,
+    // Raised from the outside to end the search early
+    pub stop: SearchStop,
+    // Called after every completed iteration, by engines that support it
+    pub report: Option<SearchReport>
+}
+
+impl Default for SearchLimits {
+    fn default() -> SearchLimits {
+        SearchLimits {
+            depth: DEFAULT_DEPTH,
+            nodes: None,
+            movetime: None,
+            threads: None,
+            multi_pv: 1,
+            stop: SearchStop::default(),
+            report: None
+        }
+    }
+}
+
+// Result of a completed iteration, `nodes` counts all search threads
+pub struct SearchProgress<'a> {
+    pub depth: usize,
+    pub seldepth: usize,
+    pub nodes: usize,
+    pub time: Duration,
+    pub lines: &'a [PvLine]
+}
+
+#[derive(Clone)]
+pub struct SearchReport(Arc<dyn Fn(&SearchProgress) + Send + Sync>);
+
+impl SearchReport {
+    pub fn new(report: impl Fn(&SearchProgress) + Send + Sync + 'static) -> SearchReport {
+        SearchReport(Arc::new(report))
+    }
+
+    pub fn send(&self, progress: &SearchProgress) {
+        (self.0)(progress)
+    }
+}
+
+impl std::fmt::Debug for SearchReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SearchReport")
+    }
+}
 
 //
 //  Traits
 //
+
+/*
+*   An engine is created once and can then search any number of positions. State that
+*   helps later searches, like the transposition table, is kept between searches.
+*/
 pub trait Engine {
-    fn new(fen: &str, depth: usize) -> Result<Self, BadRequest> where Self: Sized;
+    // Starts out in the initial position
+    fn new() -> Self where Self: Sized;
+
+    fn set_position(&mut self, fen: &str) -> Result<(), BadRequest>;
+
+    fn get_best_moves(&mut self, limits: &SearchLimits) -> (Vec<Move>, Score);
+
+    fn stats(&self) -> SearchStats;
+
+    // The best lines of the last search, best first, for engines that keep track of them
+    fn lines(&self) -> &[PvLine] {
+        &[]
+    }
+
+    // Seed for choosing between equally good moves. Without one the choice is random.
+    fn seed(&self) -> Option<u64>;
+
+    fn set_seed(&mut self, seed: Option<u64>);
 
-    fn get_best_moves(&mut self) -> (Vec<Move>, Score);
+    fn set_evaluator(&mut self, evaluator: Arc<dyn Evaluator>);
 
     fn choose_move(&self, best_moves: Vec<Move>) -> Move {
-        *best_moves.choose(&mut rand::thread_rng()).unwrap()
+        *best_moves.choose(&mut seeded_rng(self.seed())).unwrap()
     }
 
-    fn make_move(&mut self) -> (Option<Move>, Score) {
-        let (best_moves, score) = self.get_best_moves();
+    fn make_move(&mut self, limits: &SearchLimits) -> (Option<Move>, Score) {
+        let (best_moves, score) = self.get_best_moves(limits);
         if best_moves.is_empty() {
             return (None, score);
         }
@@ -214,14 +417,14 @@ impl IsPiece for Piece {
     }
     fn value(&self) -> Score {
         let value = match self.abs() {
-            1 => 1.0,
-            2 | 3 => 3.0,
-            4 => 5.0,
-            5 => 9.0,
-            6 => 1000.0,
-            _ => 0.0
+            1 => 100,
+            2 | 3 => 300,
+            4 => 500,
+            5 => 900,
+            6 => 100000,
+            _ => 0
         };
-        value * self.signum() as f64
+        value * self.signum() as Score
     }
 }
 
@@ -0,0 +1,337 @@
+use crate::types::*;
+use crate::engines::{EngineEntry, EngineOptions, ENGINES, DEFAULT_ENGINE, find_engine};
+use crate::score_functions::{EvaluatorEntry, EvalParams, EVALUATORS, DEFAULT_EVALUATOR, find_evaluator};
+use crate::skill::{Skill, MAX_SKILL_LEVEL, MIN_ELO, MAX_ELO};
+use crate::game_state::GameState;
+use crate::utils::mate_in;
+
+use itertools::Itertools;
+use std::io::{self, BufRead};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// Depth of searches that are only ended by time, a node limit or the GUI
+pub(crate) const MAX_DEPTH: usize = 64;
+// Moves left in the game when the GUI doesn't say
+const DEFAULT_MOVES_TO_GO: u64 = 30;
+// Kept in reserve for the time it takes the GUI to receive the move
+const MOVE_OVERHEAD: Duration = Duration::from_millis(50);
+const MIN_MOVE_TIME: Duration = Duration::from_millis(10);
+const MAX_MULTI_PV: usize = 64;
+
+// Engine settings that can be changed with setoption
+#[derive(Clone)]
+struct Settings {
+    engine: &'static EngineEntry,
+    evaluator: &'static EvaluatorEntry,
+    eval_params: EvalParams,
+    threads: usize,
+    multi_pv: usize,
+    skill_level: u8,
+    limit_strength: bool,
+    elo: u32
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            engine: find_engine(DEFAULT_ENGINE).unwrap(),
+            evaluator: find_evaluator(DEFAULT_EVALUATOR).unwrap(),
+            eval_params: EvalParams::default(),
+            threads: 1,
+            multi_pv: 1,
+            skill_level: MAX_SKILL_LEVEL,
+            limit_strength: false,
+            elo: MAX_ELO
+        }
+    }
+}
+
+impl Settings {
+    // UCI_Elo takes precedence over the skill level when strength is limited
+    fn skill(&self) -> Option<Skill> {
+        if self.limit_strength {
+            Some(Skill::from_elo(self.elo))
+        } else {
+            Some(Skill::new(self.skill_level)).filter(|skill| !skill.is_full_strength())
+        }
+    }
+
+    fn create_engine(&self) -> Result<Box<dyn Engine + Send>, String> {
+        let evaluator = self.evaluator.create(&self.eval_params)?;
+        Ok(self.engine.create(&EngineOptions { seed: None, skill: self.skill(), evaluator }))
+    }
+}
+
+// A search running in the background, which hands the engine back when it is done
+struct Search {
+    stop: SearchStop,
+    handle: JoinHandle<Box<dyn Engine + Send>>
+}
+
+/*
+*   Universal Chess Interface front-end for the engines. Searches run on a thread of their own
+*   so that commands like `stop` and `isready` are answered while the engine is thinking.
+*/
+pub struct Uci {
+    game: GameState,
+    settings: Settings,
+    // Taken by the search while it runs
+    engine: Option<Box<dyn Engine + Send>>,
+    search: Option<Search>
+}
+
+impl Default for Uci {
+    fn default() -> Uci {
+        let settings = Settings::default();
+        Uci {
+            game: GameState::default(),
+            engine: Some(settings.create_engine().unwrap()),
+            settings,
+            search: None
+        }
+    }
+}
+
+impl Uci {
+    // Handle one command, returning false when the GUI asks to quit
+    pub fn handle(&mut self, line: &str) -> bool {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, args)) = tokens.split_first() else {
+            return true;
+        };
+        let result = match command {
+            "uci" => {
+                self.identify();
+                Ok(())
+            },
+            "isready" => {
+                println!("readyok");
+                Ok(())
+            },
+            "ucinewgame" => self.new_game(),
+            "position" => self.position(args),
+            "go" => self.go(args),
+            "stop" => {
+                self.stop_search();
+                Ok(())
+            },
+            "setoption" => self.set_option(args),
+            "quit" => return false,
+            "debug" | "register" | "ponderhit" => Ok(()),
+            _ => Err(format!("Unknown command {command}"))
+        };
+        if let Err(err) = result {
+            println!("info string {err}");
+        }
+        true
+    }
+
+    fn identify(&self) {
+        println!("id name rust_chess {}", env!("CARGO_PKG_VERSION"));
+        println!("id author the rust_chess authors");
+        println!("option name Threads type spin default 1 min 1 max {MAX_THREADS}");
+        println!("option name MultiPV type spin default 1 min 1 max {MAX_MULTI_PV}");
+        println!("option name Engine type combo default {DEFAULT_ENGINE} {}", ENGINES.iter().map(|entry| format!("var {}", entry.name)).join(" "));
+        println!("option name Eval type combo default {DEFAULT_EVALUATOR} {}", EVALUATORS.iter().map(|entry| format!("var {}", entry.name)).join(" "));
+        println!("option name EvalParams type string default <empty>");
+        println!("option name Skill Level type spin default {MAX_SKILL_LEVEL} min 0 max {MAX_SKILL_LEVEL}");
+        println!("option name UCI_LimitStrength type check default false");
+        println!("option name UCI_Elo type spin default {MAX_ELO} min {MIN_ELO} max {MAX_ELO}");
+        println!("uciok");
+    }
+
+    // A fresh engine forgets what it learned in the previous game
+    fn new_game(&mut self) -> Result<(), String> {
+        self.stop_search();
+        self.engine = Some(self.settings.create_engine()?);
+        self.game = GameState::default();
+        Ok(())
+    }
+
+    // position (startpos | fen <fen>) [moves <move>...]
+    fn position(&mut self, args: &[&str]) -> Result<(), String> {
+        self.stop_search();
+        let moves_at = args.iter().position(|&arg| arg == "moves").unwrap_or(args.len());
+        let mut game = match args.first() {
+            Some(&"startpos") => GameState::default(),
+            Some(&"fen") => GameState::from_fen(&args[1..moves_at].join(" "))?,
+            _ => return Err("Expected startpos or fen".to_string())
+        };
+        for text in args.iter().skip(moves_at + 1) {
+            let (mv, promotion) = game.parse_move(text)?;
+            game.make_move(mv, promotion);
+        }
+        self.game = game;
+        Ok(())
+    }
+
+    // setoption name <name> [value <value>]
+    fn set_option(&mut self, args: &[&str]) -> Result<(), String> {
+        if args.first() != Some(&"name") {
+            return Err("Expected an option name".to_string());
+        }
+        let value_at = args.iter().position(|&arg| arg == "value").unwrap_or(args.len());
+        let name = args[1..value_at].join(" ");
+        let value = args.get(value_at + 1..).unwrap_or_default().join(" ");
+        let number = || value.parse::<usize>().map_err(|_| format!("Invalid value {value} for {name}"));
+        let mut settings = self.settings.clone();
+        // Option names are case insensitive
+        match name.to_lowercase().as_str() {
+            "threads" => settings.threads = number()?.clamp(1, MAX_THREADS),
+            "multipv" => settings.multi_pv = number()?.clamp(1, MAX_MULTI_PV),
+            "engine" => settings.engine = find_engine(&value).ok_or(format!("Unknown engine {value}"))?,
+            "eval" => settings.evaluator = find_evaluator(&value).ok_or(format!("Unknown evaluator {value}"))?,
+            "evalparams" if value.is_empty() || value == "<empty>" => settings.eval_params = EvalParams::default(),
+            "evalparams" => settings.eval_params = EvalParams::from_file(&value)?,
+            "skill level" => settings.skill_level = number()?.min(MAX_SKILL_LEVEL as usize) as u8,
+            "uci_limitstrength" => settings.limit_strength = value == "true",
+            "uci_elo" => settings.elo = number()?.clamp(MIN_ELO as usize, MAX_ELO as usize) as u32,
+            _ => return Err(format!("Unknown option {name}"))
+        }
+        self.stop_search();
+        self.engine = Some(settings.create_engine()?);
+        self.settings = settings;
+        Ok(())
+    }
+
+    // go [depth <n>] [nodes <n>] [movetime <ms>] [wtime <ms>] [btime <ms>] [winc <ms>] [binc <ms>] [movestogo <n>] [infinite]
+    fn go(&mut self, args: &[&str]) -> Result<(), String> {
+        self.stop_search();
+        let value = |name: &str| {
+            args.iter().position(|&arg| arg == name)
+                .and_then(|i| args.get(i + 1))
+                .and_then(|value| value.parse::<i64>().ok())
+                .map(|value| value.max(0) as u64)
+        };
+        let (time, increment) = if self.game.player().is_white() {("wtime", "winc")} else {("btime", "binc")};
+        let movetime = value("movetime").map(Duration::from_millis).or_else(|| {
+            value(time).map(|time| time_for_move(Duration::from_millis(time), Duration::from_millis(value(increment).unwrap_or(0)), value("movestogo")))
+        });
+        let limits = SearchLimits {
+            depth: value("depth").map_or(MAX_DEPTH, |depth| depth.max(1) as usize),
+            nodes: value("nodes").map(|nodes| nodes as usize),
+            movetime,
+            threads: Some(self.settings.threads),
+            multi_pv: self.settings.multi_pv,
+            stop: SearchStop::default(),
+            report: Some(report_progress(self.game.clone()))
+        };
+        // Without any limit the search runs until it is stopped
+        let infinite = args.contains(&"infinite") || (limits.depth == MAX_DEPTH && limits.nodes.is_none() && limits.movetime.is_none());
+
+        let mut engine = self.engine.take().unwrap();
+        if let Err(err) = engine.set_position(&self.game.fen()) {
+            self.engine = Some(engine);
+            return Err(err.0.unwrap_or("Invalid position").to_string());
+        }
+        let game = self.game.clone();
+        let stop = limits.stop.clone();
+        let weakened = self.settings.skill().is_some();
+        let handle = thread::spawn(move || {
+            let (mv, _) = engine.make_move(&limits);
+            // The move of the pv sent last, unless a weakened engine picked another line on purpose
+            let mv = if weakened {mv} else {top_move(engine.as_ref()).or(mv)};
+            // The best move of an infinite search may only be sent once the GUI stops it
+            while infinite && !limits.stop.is_stopped() {
+                thread::sleep(Duration::from_millis(5));
+            }
+            // Stopped before the first iteration was done
+            let mv = mv.or_else(|| game.legal_moves().first().copied());
+            println!("bestmove {}", mv.map_or("0000".to_string(), |mv| game.move_to_string(mv)));
+            engine
+        });
+        self.search = Some(Search { stop, handle });
+        Ok(())
+    }
+
+    fn stop_search(&mut self) {
+        if let Some(search) = self.search.take() {
+            search.stop.stop();
+            self.engine = Some(search.handle.join().expect("Search thread panicked"));
+        }
+    }
+}
+
+// Read commands from standard input until the GUI quits
+pub fn run() {
+    let mut uci = Uci::default();
+    for line in io::stdin().lock().lines().map_while(Result::ok) {
+        if !uci.handle(&line) {
+            break;
+        }
+    }
+    uci.stop_search();
+}
+
+// First move of the best line, which an engine that picks between equal moves at random may not play
+pub(crate) fn top_move(engine: &(dyn Engine + Send)) -> Option<Move> {
+    engine.lines().first().and_then(|line| line.moves.first().copied())
+}
+
+// An even share of the remaining time plus most of the increment
+pub(crate) fn time_for_move(time_left: Duration, increment: Duration, moves_to_go: Option<u64>) -> Duration {
+    let share = time_left / moves_to_go.unwrap_or(DEFAULT_MOVES_TO_GO).max(1) as u32 + increment * 3 / 4;
+    share.min(time_left.saturating_sub(MOVE_OVERHEAD)).max(MIN_MOVE_TIME)
+}
+
+// One info line per best line after every iteration
+fn report_progress(game: GameState) -> SearchReport {
+    SearchReport::new(move |progress: &SearchProgress| {
+        let nps = (progress.nodes as f64 / progress.time.as_secs_f64().max(1e-3)) as u64;
+        for (i, line) in progress.lines.iter().enumerate() {
+            println!(
+                "info depth {} seldepth {} multipv {} score {} nodes {} nps {nps} time {} pv {}",
+                progress.depth,
+                progress.seldepth,
+                i + 1,
+                score_string(line.score, game.player()),
+                progress.nodes,
+                progress.time.as_millis(),
+                game.line_to_string(&line.moves)
+            );
+        }
+    })
+}
+
+// Scores are from the point of view of the side to move
+fn score_string(score: Score, player: Player) -> String {
+    match mate_in(score) {
+        Some(moves) => format!("mate {}", moves * player as i32),
+        None => format!("cp {}", score * player as Score)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn even_share_of_the_time_left() {
+        assert_eq!(time_for_move(ms(60_000), ms(0), None), ms(2000));
+        assert_eq!(time_for_move(ms(60_000), ms(0), Some(10)), ms(6000));
+    }
+
+    #[test]
+    fn most_of_the_increment_is_used() {
+        assert_eq!(time_for_move(ms(10_000), ms(1000), Some(10)), ms(1750));
+    }
+
+    #[test]
+    fn overhead_is_kept_in_reserve() {
+        assert_eq!(time_for_move(ms(1000), ms(0), Some(1)), ms(950));
+        // A GUI sending movestogo 0 gets the same as for the last move before the time control
+        assert_eq!(time_for_move(ms(1000), ms(0), Some(0)), ms(950));
+        assert_eq!(time_for_move(ms(1000), ms(2000), Some(5)), ms(950));
+    }
+
+    #[test]
+    fn never_below_the_minimum() {
+        assert_eq!(time_for_move(ms(30), ms(0), None), MIN_MOVE_TIME);
+        assert_eq!(time_for_move(ms(0), ms(0), Some(1)), MIN_MOVE_TIME);
+    }
+}
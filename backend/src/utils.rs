@@ -1,4 +1,6 @@
 use rocket::response::status;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
 
 use crate::types::*;
 
@@ -20,12 +22,12 @@ lazy_static! {
 }
 
 pub const INDEX_TO_CHAR: [char; 8] = ['a', 'b', 'c', 'd', 'e', 'f', 'g', 'h'];
+// By piece type, black pieces are lower case
+const PIECE_CHARS: [char; 7] = [' ', 'p', 'n', 'b', 'r', 'q', 'k'];
 
 
-pub fn _move_to_string((from, to): Move) -> String {
-    let from = (INDEX_TO_CHAR[from.0], from.1);
-    let to = (INDEX_TO_CHAR[to.0], to.1);
-    format!("{}{}, {}{}", from.0, from.1+1, to.0, to.1+1)
+pub fn move_to_string((from, to): Move) -> String {
+    format!("{}{}", pos_to_string(from), pos_to_string(to))
 }
 
 pub fn pos_to_string((x, y): Position) -> String {
@@ -109,24 +111,28 @@ fn en_passant_from_fen(fen: &str) -> Result<Option<Position>, BadRequest> {
         return Ok(None);
     }
     let chars: Vec<char> = fen.chars().collect();
-    let x = CHAR_TO_INDEX.get(&chars[0]);
-    let y = chars[1].to_digit(10);
+    let x = chars.first().and_then(|ch| CHAR_TO_INDEX.get(ch));
+    let y = chars.get(1).and_then(|ch| ch.to_digit(10)).filter(|y| (1..=8).contains(y));
     if x.is_none() || y.is_none() {
         return Err(status::BadRequest(Some("Invalid en passant information!")));
     }
-    Ok(Some((*x.unwrap(), y.unwrap() as usize)))
+    Ok(Some((*x.unwrap(), y.unwrap() as usize - 1)))
 }
 
+// The move counters may be left out, as in EPD
 pub fn parse_fen(fen: &str) -> Result<FenData, BadRequest> {
     // Split parts of FEN
     let fen: Vec<&str> = fen.split_whitespace().collect();
+    if fen.len() < 4 {
+        return Err(status::BadRequest(Some("Incomplete FEN")));
+    }
         
     let board = board_from_fen(fen[0])?;
     let player = player_from_fen(fen[1])?;
     let can_castle = castle_from_fen(fen[2])?;
     let en_passant = en_passant_from_fen(fen[3])?;
-    let half_moves = fen[4].parse();
-    let move_number = fen[5].parse();
+    let half_moves = fen.get(4).unwrap_or(&"0").parse();
+    let move_number = fen.get(5).unwrap_or(&"1").parse();
 
     if half_moves.is_err() || move_number.is_err() {
         return Err(status::BadRequest(Some("Error in fen parsing!")));
@@ -145,16 +151,55 @@ pub fn parse_fen(fen: &str) -> Result<FenData, BadRequest> {
     })
 }
 
-pub fn cmp_scores(a: &Score, b: &Score) -> std::cmp::Ordering {
-    a.partial_cmp(b).unwrap()
+pub fn to_fen(data: &FenData) -> String {
+    let rows: Vec<String> = (0..8).rev().map(|y| {
+        let mut row = String::new();
+        let mut empty = 0;
+        for x in 0..8 {
+            let piece = data.board[(x, y)];
+            if piece == 0 {
+                empty += 1;
+                continue;
+            }
+            if empty > 0 {
+                row.push_str(&empty.to_string());
+                empty = 0;
+            }
+            let ch = PIECE_CHARS[piece.unsigned_abs() as usize];
+            row.push(if piece.is_white() {ch.to_ascii_uppercase()} else {ch});
+        }
+        if empty > 0 {
+            row.push_str(&empty.to_string());
+        }
+        row
+    }).collect();
+    let castle: String = ['K', 'Q', 'k', 'q'].into_iter().filter(|&ch| data.can_castle[ch]).collect();
+    format!(
+        "{} {} {} {} {} {}",
+        rows.join("/"),
+        if data.player.is_positive() {"w"} else {"b"},
+        if castle.is_empty() {"-".to_string()} else {castle},
+        data.en_passant.map_or("-".to_string(), pos_to_string),
+        data.half_moves,
+        data.move_number
+    )
 }
 
-pub fn max_score(a: Score, b: Score) -> Score {
-    std::cmp::max_by(a, b, cmp_scores)
+// Random generator for choosing between moves, reproducible when a seed is given
+pub fn seeded_rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy()
+    }
 }
 
-pub fn min_score(a: Score, b: Score) -> Score {
-    std::cmp::min_by(a, b, cmp_scores)
+// Number of moves until mate if the score is a mate score, negative if black mates
+pub fn mate_in(score: Score) -> Option<i32> {
+    if score.abs() <= SCORE_MATE_BOUND {
+        return None;
+    }
+    let plies = SCORE_MATE - score.abs();
+    Some(score.signum() * (plies + 1) / 2)
 }
 
 pub fn _valid_pos((x, y): Position) -> bool {
@@ -0,0 +1,399 @@
+use crate::types::*;
+use crate::engines::{EngineEntry, EngineOptions, ENGINES, DEFAULT_ENGINE, find_engine};
+use crate::score_functions::{EvaluatorEntry, EvalParams, EVALUATORS, DEFAULT_EVALUATOR, find_evaluator};
+use crate::game_state::GameState;
+use crate::uci::{time_for_move, top_move, MAX_DEPTH};
+use crate::utils::mate_in;
+
+use itertools::Itertools;
+use std::io::{self, BufRead};
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// Mate scores in the thinking output, plus or minus the number of moves to mate
+const MATE_SCORE: i32 = 100000;
+// Clock of a game that hasn't been given a time control
+const DEFAULT_TIME: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Copy, Debug)]
+enum TimeControl {
+    // st, the same time for every move
+    PerMove(Duration),
+    // level, a number of moves per session (or all of them) and an increment
+    Clock { moves_per_session: Option<u64>, increment: Duration }
+}
+
+// Commands from the GUI and searches that are done arrive on the same channel, in order
+enum Event {
+    Command(String),
+    SearchDone(usize)
+}
+
+struct Search {
+    id: usize,
+    stop: SearchStop,
+    handle: JoinHandle<(Box<dyn Engine + Send>, Option<Move>)>
+}
+
+/*
+*   Chess Engine Communication Protocol (xboard) front-end for the engines. Unlike with UCI the
+*   engine keeps track of the game itself, plays its own moves when it is on the move and tells
+*   the GUI when the game has ended.
+*/
+pub struct XBoard {
+    game: GameState,
+    engine_entry: &'static EngineEntry,
+    evaluator: &'static EvaluatorEntry,
+    // Taken by the search while it runs
+    engine: Option<Box<dyn Engine + Send>>,
+    // The side the engine plays, none in force mode
+    engine_side: Option<Player>,
+    time_control: TimeControl,
+    time_left: Duration,
+    depth: Option<usize>,
+    threads: usize,
+    post: bool,
+    search: Option<Search>,
+    searches: usize,
+    events: Sender<Event>
+}
+
+impl XBoard {
+    fn new(events: Sender<Event>) -> XBoard {
+        let engine_entry = find_engine(DEFAULT_ENGINE).unwrap();
+        let evaluator = find_evaluator(DEFAULT_EVALUATOR).unwrap();
+        XBoard {
+            game: GameState::default(),
+            engine: Some(create_engine(engine_entry, evaluator).unwrap()),
+            engine_entry,
+            evaluator,
+            engine_side: Some(-1),
+            time_control: TimeControl::Clock { moves_per_session: None, increment: Duration::ZERO },
+            time_left: DEFAULT_TIME,
+            depth: None,
+            threads: 1,
+            post: false,
+            search: None,
+            searches: 0,
+            events
+        }
+    }
+
+    // Handle one command, returning false when the GUI asks to quit
+    fn handle(&mut self, line: &str) -> bool {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, args)) = tokens.split_first() else {
+            return true;
+        };
+        let arg = args.first().copied().unwrap_or_default();
+        let result = match command {
+            "protover" => {
+                self.features();
+                Ok(())
+            },
+            "new" => self.new_game(),
+            "force" => {
+                self.cancel_search();
+                self.engine_side = None;
+                Ok(())
+            },
+            "go" => {
+                self.cancel_search();
+                self.engine_side = Some(self.game.player());
+                self.think()
+            },
+            "playother" => {
+                self.cancel_search();
+                self.engine_side = Some(-self.game.player());
+                Ok(())
+            },
+            "usermove" => self.user_move(arg),
+            "level" => self.level(args),
+            "st" => parse_number(arg).map(|secs| self.time_control = TimeControl::PerMove(Duration::from_secs(secs))),
+            "sd" => parse_number(arg).map(|depth| self.depth = Some(depth.max(1) as usize)),
+            "time" => parse_number(arg).map(|centis| self.time_left = Duration::from_millis(centis * 10)),
+            "cores" => parse_number(arg).map(|cores| self.threads = cores.max(1) as usize),
+            "undo" => self.take_back(1),
+            "remove" => self.take_back(2),
+            "setboard" => self.set_board(&args.join(" ")),
+            "post" => {
+                self.post = true;
+                Ok(())
+            },
+            "nopost" => {
+                self.post = false;
+                Ok(())
+            },
+            // The game is over, the engine doesn't move until the next one
+            "result" => {
+                self.cancel_search();
+                self.engine_side = None;
+                Ok(())
+            },
+            // Move now
+            "?" => {
+                if let Some(search) = &self.search {
+                    search.stop.stop();
+                }
+                Ok(())
+            },
+            "ping" => {
+                println!("pong {arg}");
+                Ok(())
+            },
+            "option" => self.set_option(&args.join(" ")),
+            "quit" => return false,
+            "xboard" | "accepted" | "rejected" | "otim" | "random" | "hard" | "easy" | "computer" | "name" | "rating" | "ics" | "memory" | "white" | "black" | "draw" | "hint" | "bk" => Ok(()),
+            _ => Err("unknown command".to_string())
+        };
+        if let Err(err) = result {
+            println!("Error ({err}): {line}");
+        }
+        true
+    }
+
+    fn features(&self) {
+        let options = [
+            format!("Engine -combo {}", ENGINES.iter().map(|entry| if entry.name == DEFAULT_ENGINE {format!("*{}", entry.name)} else {entry.name.to_string()}).join(" /// ")),
+            format!("Eval -combo {}", EVALUATORS.iter().map(|entry| if entry.name == DEFAULT_EVALUATOR {format!("*{}", entry.name)} else {entry.name.to_string()}).join(" /// "))
+        ];
+        println!("feature done=0");
+        println!("feature myname=\"rust_chess {}\" usermove=1 setboard=1 ping=1 playother=1 colors=0 analyze=0 smp=1 sigint=0 sigterm=0 reuse=1", env!("CARGO_PKG_VERSION"));
+        for option in options {
+            println!("feature option=\"{option}\"");
+        }
+        println!("feature done=1");
+    }
+
+    // A fresh engine forgets what it learned in the previous game
+    fn new_game(&mut self) -> Result<(), String> {
+        self.cancel_search();
+        self.engine = Some(create_engine(self.engine_entry, self.evaluator)?);
+        self.game = GameState::default();
+        self.engine_side = Some(-1);
+        self.depth = None;
+        Ok(())
+    }
+
+    fn user_move(&mut self, text: &str) -> Result<(), String> {
+        self.cancel_search();
+        let (mv, promotion) = match self.game.parse_move(text) {
+            Ok(parsed) => parsed,
+            Err(_) => {
+                println!("Illegal move: {text}");
+                return Ok(());
+            }
+        };
+        self.game.make_move(mv, promotion);
+        if !self.report_outcome() && self.engine_side == Some(self.game.player()) {
+            self.think()?;
+        }
+        Ok(())
+    }
+
+    // level <moves per session> <minutes or minutes:seconds> <increment in seconds>
+    fn level(&mut self, args: &[&str]) -> Result<(), String> {
+        let [moves, base, increment] = args else {
+            return Err("expected moves, time and increment".to_string());
+        };
+        let moves = parse_number(moves)?;
+        let base = match base.split_once(':') {
+            Some((minutes, seconds)) => parse_number(minutes)? * 60 + parse_number(seconds)?,
+            None => parse_number(base)? * 60
+        };
+        let increment = increment.parse::<f64>().ok().filter(|increment| *increment >= 0.0).ok_or("invalid increment")?;
+        self.time_control = TimeControl::Clock {
+            moves_per_session: (moves > 0).then_some(moves),
+            increment: Duration::from_secs_f64(increment)
+        };
+        self.time_left = Duration::from_secs(base);
+        Ok(())
+    }
+
+    fn take_back(&mut self, moves: usize) -> Result<(), String> {
+        self.cancel_search();
+        for _ in 0..moves {
+            if !self.game.undo() {
+                return Err("no moves to take back".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    fn set_board(&mut self, fen: &str) -> Result<(), String> {
+        self.cancel_search();
+        self.game = GameState::from_fen(fen).inspect_err(|_| println!("tellusererror Illegal position"))?;
+        Ok(())
+    }
+
+    // option <name>=<value>
+    fn set_option(&mut self, option: &str) -> Result<(), String> {
+        let (name, value) = option.split_once('=').ok_or("expected name=value")?;
+        let (mut engine_entry, mut evaluator) = (self.engine_entry, self.evaluator);
+        match name {
+            "Engine" => engine_entry = find_engine(value).ok_or(format!("unknown engine {value}"))?,
+            "Eval" => evaluator = find_evaluator(value).ok_or(format!("unknown evaluator {value}"))?,
+            _ => return Err(format!("unknown option {name}"))
+        }
+        self.cancel_search();
+        self.engine = Some(create_engine(engine_entry, evaluator)?);
+        (self.engine_entry, self.evaluator) = (engine_entry, evaluator);
+        Ok(())
+    }
+
+    fn movetime(&self) -> Duration {
+        match self.time_control {
+            TimeControl::PerMove(time) => time_for_move(time, Duration::ZERO, Some(1)),
+            TimeControl::Clock { moves_per_session, increment } => {
+                // The move number counts the moves each side has made before this one
+                let moves_to_go = moves_per_session.map(|moves| moves - (self.game.position().move_number as u64 - 1) % moves);
+                time_for_move(self.time_left, increment, moves_to_go)
+            }
+        }
+    }
+
+    // Start searching for the engine's move, which is played when the search is done
+    fn think(&mut self) -> Result<(), String> {
+        if self.game.outcome().is_some() {
+            return Ok(());
+        }
+        let limits = SearchLimits {
+            depth: self.depth.unwrap_or(MAX_DEPTH),
+            movetime: Some(self.movetime()),
+            threads: Some(self.threads),
+            report: self.post.then(|| report_thinking(self.game.clone())),
+            ..Default::default()
+        };
+        let mut engine = self.engine.take().unwrap();
+        if let Err(err) = engine.set_position(&self.game.fen()) {
+            self.engine = Some(engine);
+            return Err(err.0.unwrap_or("Invalid position").to_string());
+        }
+        self.searches += 1;
+        let id = self.searches;
+        let stop = limits.stop.clone();
+        let events = self.events.clone();
+        let handle = thread::spawn(move || {
+            let (mv, _) = engine.make_move(&limits);
+            // The move of the line sent as thinking output
+            let mv = top_move(engine.as_ref()).or(mv);
+            // Nothing is listening any more once the GUI has quit
+            let _ = events.send(Event::SearchDone(id));
+            (engine, mv)
+        });
+        self.search = Some(Search { id, stop, handle });
+        Ok(())
+    }
+
+    // Play the engine's move, unless the search was cancelled in the meantime
+    fn search_done(&mut self, id: usize) {
+        if self.search.as_ref().map(|search| search.id) != Some(id) {
+            return;
+        }
+        let search = self.search.take().unwrap();
+        let (engine, mv) = search.handle.join().expect("Search thread panicked");
+        self.engine = Some(engine);
+        // Stopped before the first iteration was done
+        let Some(mv) = mv.or_else(|| self.game.legal_moves().first().copied()) else {
+            return;
+        };
+        println!("move {}", self.game.move_to_string(mv));
+        self.game.make_move(mv, None);
+        self.report_outcome();
+    }
+
+    // Stop the search without playing its move
+    fn cancel_search(&mut self) {
+        if let Some(search) = self.search.take() {
+            search.stop.stop();
+            self.engine = Some(search.handle.join().expect("Search thread panicked").0);
+        }
+    }
+
+    // Returns whether the game is over
+    fn report_outcome(&mut self) -> bool {
+        match self.game.outcome() {
+            Some(outcome) => {
+                println!("{} {{{}}}", outcome.result(), outcome.reason());
+                self.engine_side = None;
+                true
+            },
+            None => false
+        }
+    }
+}
+
+// Read commands from standard input until the GUI quits
+pub fn run() {
+    let (events, receiver) = mpsc::channel();
+    let commands = events.clone();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines().map_while(Result::ok) {
+            if commands.send(Event::Command(line)).is_err() {
+                return;
+            }
+        }
+        let _ = commands.send(Event::Command("quit".to_string()));
+    });
+
+    let mut xboard = XBoard::new(events);
+    for event in receiver {
+        match event {
+            Event::Command(line) => if !xboard.handle(&line) {
+                break;
+            },
+            Event::SearchDone(id) => xboard.search_done(id)
+        }
+    }
+    xboard.cancel_search();
+}
+
+fn create_engine(engine: &EngineEntry, evaluator: &EvaluatorEntry) -> Result<Box<dyn Engine + Send>, String> {
+    let evaluator = evaluator.create(&EvalParams::default())?;
+    Ok(engine.create(&EngineOptions { seed: None, skill: None, evaluator }))
+}
+
+fn parse_number(text: &str) -> Result<u64, String> {
+    text.parse().map_err(|_| format!("invalid number {text}"))
+}
+
+// ply, score, time in centiseconds, nodes and the best line
+fn report_thinking(game: GameState) -> SearchReport {
+    SearchReport::new(move |progress: &SearchProgress| {
+        let Some(line) = progress.lines.first() else {
+            return;
+        };
+        // Scores are from the point of view of the engine
+        let player = game.player() as i32;
+        let score = match mate_in(line.score) {
+            Some(moves) if moves * player > 0 => MATE_SCORE + moves * player,
+            Some(moves) => -MATE_SCORE + moves * player,
+            None => line.score * player
+        };
+        println!("{} {score} {} {} {}", progress.depth, progress.time.as_millis() / 10, progress.nodes, game.line_to_string(&line.moves));
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    // Without sd the search only ends when it is stopped, which has to work for every engine
+    #[test]
+    fn every_engine_can_be_interrupted() {
+        for entry in ENGINES {
+            let (events, _receiver) = mpsc::channel();
+            let mut xboard = XBoard::new(events);
+            for command in ["new", &format!("option Engine={}", entry.name), "go"] {
+                assert!(xboard.handle(command));
+            }
+            thread::sleep(Duration::from_millis(100));
+            let start = Instant::now();
+            assert!(xboard.handle("force"));
+            assert!(xboard.search.is_none() && xboard.engine.is_some());
+            assert!(start.elapsed() < Duration::from_secs(5), "{}", entry.name);
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use crate::types::*;
+use crate::game::all_coords;
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+struct ZobristKeys {
+    pieces: [[u64; 13]; 64],
+    castle: [u64; 16],
+    en_passant: [u64; 8],
+    black: u64
+}
+
+lazy_static! {
+    // Seeded so that hashes are identical between runs
+    static ref KEYS: ZobristKeys = {
+        let mut rng = StdRng::seed_from_u64(0x5eed_c0ff_ee15_600d);
+        let mut keys = ZobristKeys { pieces: [[0; 13]; 64], castle: [0; 16], en_passant: [0; 8], black: rng.gen() };
+        for square in keys.pieces.iter_mut() {
+            // Index 6 is the empty square and is left as 0 so that empty squares don't affect the hash
+            for (_, key) in square.iter_mut().enumerate().filter(|&(i, _)| i != 6) {
+                *key = rng.gen();
+            }
+        }
+        keys.castle.iter_mut().for_each(|key| *key = rng.gen());
+        keys.en_passant.iter_mut().for_each(|key| *key = rng.gen());
+        keys
+    };
+}
+
+pub fn piece_key(piece: Piece, (x, y): Position) -> u64 {
+    KEYS.pieces[8*y + x][(piece + 6) as usize]
+}
+
+pub fn castle_key(can_castle: &CastleStatus) -> u64 {
+    let index = can_castle.white.k as usize
+        | (can_castle.white.q as usize) << 1
+        | (can_castle.black.k as usize) << 2
+        | (can_castle.black.q as usize) << 3;
+    KEYS.castle[index]
+}
+
+pub fn en_passant_key(en_passant: Option<Position>) -> u64 {
+    en_passant.map_or(0, |(x, _)| KEYS.en_passant[x])
+}
+
+pub fn player_key(player: Player) -> u64 {
+    if player.is_positive() {
+        0
+    } else {
+        KEYS.black
+    }
+}
+
+// Hash a position from scratch. Engines keep the hash up to date incrementally in do_move / undo_move.
+pub fn hash_position(board: &Board, player: Player, can_castle: &CastleStatus, en_passant: Option<Position>) -> u64 {
+    let mut hash = castle_key(can_castle) ^ en_passant_key(en_passant) ^ player_key(player);
+    for pos in all_coords() {
+        hash ^= piece_key(board[pos], pos);
+    }
+    hash
+}
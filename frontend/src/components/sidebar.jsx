@@ -1,23 +1,55 @@
-import React from "react"
+import React, { useEffect, useState } from "react"
 import { useDispatch, useSelector } from "react-redux"
 import { ActionCreators } from "redux-undo"
 
 import { gameSelector } from "../model/game"
-import { requestAnalysisThunk } from "../model/utils"
+import { requestAnalysisThunk, fetchEngines, fetchEvalTrace, formatScore } from "../model/utils"
 
 import "../styles/sidebar.css"
 
 function Sidebar() {
     const dispatch = useDispatch()
     const score = useSelector(gameSelector("computerScore"))
+    const [engines, setEngines] = useState([])
+    const [engine, setEngine] = useState("")
+    const game = useSelector(gameSelector())
+    const [trace, setTrace] = useState(null)
 
+    useEffect(() => {
+        fetchEngines().then(setEngines).catch(console.error)
+    }, [])
+
+    // The breakdown belongs to the position it was requested for
+    useEffect(() => setTrace(null), [game])
+
+    const explain = () => fetchEvalTrace(game).then(setTrace).catch(console.error)
 
     return <div className="sidebar">
         <button onClick={() => dispatch(ActionCreators.undo())}>Undo</button>
-        <button onClick={() => dispatch(requestAnalysisThunk())}>Get computer move</button>
+        <select value={engine} onChange={e => setEngine(e.target.value)}>
+            <option value="">Default engine</option>
+            {engines.map(({name, description}) => <option key={name} value={name} title={description}>{name}</option>)}
+        </select>
+        <button onClick={() => dispatch(requestAnalysisThunk(engine))}>Get computer move</button>
         {score != null ? <div>
             Computer score: <span>{score}</span>
         </div>: ""}
+        <button onClick={explain}>Explain evaluation</button>
+        {trace ? <table className="trace">
+            <thead>
+                <tr><th></th><th>White</th><th>Black</th></tr>
+            </thead>
+            <tbody>
+                {trace.terms.map(({name, white, black}) => <tr key={name}>
+                    <td>{name}</td>
+                    <td>{formatScore({score: white})}</td>
+                    <td>{formatScore({score: black})}</td>
+                </tr>)}
+            </tbody>
+            <tfoot>
+                <tr><td>Total</td><td colSpan="2">{formatScore({score: trace.total})}</td></tr>
+            </tfoot>
+        </table> : ""}
     </div>
 }
 
@@ -4,19 +4,39 @@ const pieceToChar = [..." pnbrqk"]
 const indexToChar = [..."abcdefgh"]
 const charToIndex = {a: 0, b:1, c: 2, d: 3, e: 4, f: 5, g: 6, h: 7}
 
-function requestAnalysisThunk() {
+function requestAnalysisThunk(engine) {
     return async (dispatch, getState) => {
         const state = getState().game.present
         const fen = toFEN(state)
-        const URL = API_URL + "/make_move?" + new URLSearchParams({fen})
+        const params = engine ? {fen, engine} : {fen}
+        const URL = API_URL + "/make_move?" + new URLSearchParams(params)
         const res = await fetch(URL).then(res => res.json())
         console.log(res)
         if (!res.checkmate) {
-            dispatch({type: "game/TRY_MOVE", payload: {from: algPosToNum(res.from), to: algPosToNum(res.to), computerScore: res.score}})
+            dispatch({type: "game/TRY_MOVE", payload: {from: algPosToNum(res.from), to: algPosToNum(res.to), computerScore: formatScore(res)}})
         }
     }
 }
 
+// The engines the backend can play with, each with a name and the parameters it supports
+async function fetchEngines() {
+    return await fetch(API_URL + "/list").then(res => res.json())
+}
+
+// Static evaluation of the position split into terms, with a score for white and black in each
+async function fetchEvalTrace(state) {
+    const URL = API_URL + "/eval?" + new URLSearchParams({fen: toFEN(state)})
+    return await fetch(URL).then(res => res.json())
+}
+
+// Scores are sent in centipawns, or as moves until mate when the engine has found one
+function formatScore({score, mate}) {
+    if (mate != null) {
+        return `M${mate}`
+    }
+    return (score / 100).toFixed(2)
+}
+
 function algPosToNum(pos) {
     return {x: charToIndex[pos[0]], y: parseInt(pos[1]) - 1}
 }
@@ -117,5 +137,8 @@ export {
     eqPos,
     isWhite,
     backRank,
-    requestAnalysisThunk
+    requestAnalysisThunk,
+    fetchEngines,
+    fetchEvalTrace,
+    formatScore
 }
\ No newline at end of file